@@ -2,16 +2,23 @@ use anyhow::Result;
 use std::{env, io, net::Ipv4Addr, str};
 use toytcp::tcp::TCP;
 
+const INTERRUPT: u8 = 0x03; // ETX(Ctrl-C)
+
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     let addr: Ipv4Addr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
-    echo_client(addr, port)?;
+    let md5_key = args.get(3).map(|s| s.as_bytes());
+    echo_client(addr, port, md5_key)?;
     Ok(())
 }
 
-fn echo_client(remote_addr: Ipv4Addr, remote_port: u16) -> Result<()> {
+fn echo_client(remote_addr: Ipv4Addr, remote_port: u16, md5_key: Option<&[u8]>) -> Result<()> {
     let tcp = TCP::new();
+    if md5_key.is_some() {
+        // サーバーと共有した鍵でセグメントを署名する
+        tcp.set_connect_md5_key(remote_addr, md5_key)?;
+    }
     let sock_id = tcp.connect(remote_addr, remote_port)?;
     let cloned_tcp = tcp.clone();
     ctrlc::set_handler(move || {
@@ -21,6 +28,11 @@ fn echo_client(remote_addr: Ipv4Addr, remote_port: u16) -> Result<()> {
     loop {
         let mut input = String::new();
         io::stdin().read_line(&mut input)?;
+        if input.trim() == "!" {
+            // telnetの割り込みのように，緊急データとして送る
+            tcp.send_urgent(sock_id, &[INTERRUPT])?;
+            continue;
+        }
 
         tcp.send(sock_id, input.as_bytes())?;
 
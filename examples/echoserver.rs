@@ -1,18 +1,45 @@
 use anyhow::Result;
-use std::{env, net::Ipv4Addr, str};
-use toytcp::tcp::TCP;
+use std::{env, net::Ipv4Addr, str, time::Duration};
+use toytcp::tcp::{Keepalive, TCP};
+
+const BACKLOG: usize = 16;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     let addr: Ipv4Addr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
-    echo_server(addr, port)?;
+    // MD5署名を使うクライアントのアドレスと鍵
+    let md5_peer = match (args.get(3), args.get(4)) {
+        (Some(peer), Some(key)) => Some((peer.parse()?, key.as_bytes())),
+        _ => None,
+    };
+    echo_server(addr, port, md5_peer)?;
     Ok(())
 }
 
-fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
+fn echo_server(
+    local_addr: Ipv4Addr,
+    local_port: u16,
+    md5_peer: Option<(Ipv4Addr, &[u8])>,
+) -> Result<()> {
     let tcp = TCP::new();
-    let listening_socket = tcp.listen(local_addr, local_port)?;
+    let listening_socket = tcp.listen(local_addr, local_port, BACKLOG)?;
+    // 相手が消えた接続をいつまでも残さないように，acceptしたソケットでキープアライブする
+    tcp.set_keepalive(
+        listening_socket,
+        Some(Keepalive {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            count: 3,
+        }),
+    )?;
+    // SYN floodで半開きの接続が溢れても，正しいクライアントは接続できるようにする
+    tcp.set_syn_cookies(listening_socket, true)?;
+    // 短い要求をSYNに載せて送ってくるクライアントに，1RTT早く応答する
+    tcp.set_fast_open(listening_socket, true)?;
+    if let Some((peer, key)) = md5_peer {
+        tcp.set_md5_key(listening_socket, peer, Some(key))?;
+    }
     dbg!("listening..");
     loop {
         let connected_socket = tcp.accept(listening_socket)?;
@@ -22,7 +49,17 @@ fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
         std::thread::spawn(move || {
             let mut buffer = [0; 1024];
             loop {
-                let nbytes = cloned_tcp.recv(connected_socket, &mut buffer).unwrap();
+                let nbytes = match cloned_tcp.recv(connected_socket, &mut buffer) {
+                    Ok(nbytes) => nbytes,
+                    Err(error) => {
+                        dbg!(error);
+                        cloned_tcp.close(connected_socket).unwrap();
+                        return;
+                    }
+                };
+                if let Some(byte) = cloned_tcp.recv_urgent(connected_socket).unwrap() {
+                    println!("urgent data: {:#04x}", byte);
+                }
                 if nbytes == 0 {
                     dbg!("closing connection...");
                     cloned_tcp.close(connected_socket).unwrap();
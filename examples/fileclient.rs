@@ -1,5 +1,5 @@
 use anyhow::Result;
-use std::{env, fs, net::Ipv4Addr, str};
+use std::{env, fs, net::Ipv4Addr, net::Shutdown, str};
 use toytcp::tcp::TCP;
 
 fn main() -> Result<()> {
@@ -7,12 +7,22 @@ fn main() -> Result<()> {
     let addr: Ipv4Addr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
     let filepath: &str = &args[3];
-    file_client(addr, port, filepath)?;
+    let congestion_control = args.get(4).map(|s| s.as_str());
+    file_client(addr, port, filepath, congestion_control)?;
     Ok(())
 }
 
-fn file_client(remote_addr: Ipv4Addr, remote_port: u16, filepath: &str) -> Result<()> {
+fn file_client(
+    remote_addr: Ipv4Addr,
+    remote_port: u16,
+    filepath: &str,
+    congestion_control: Option<&str>,
+) -> Result<()> {
     let tcp = TCP::new();
+    // 経路上のルーターが対応していれば，パケットを捨てる代わりにCEマークで輻輳を知らせてもらう
+    tcp.set_ecn(true);
+    // SYNでECNを交渉できるように，connectの前にアルゴリズムを決めておく
+    tcp.set_connect_congestion_control(remote_addr, congestion_control)?;
     let sock_id = tcp.connect(remote_addr, remote_port)?;
     let cloned_tcp = tcp.clone();
     ctrlc::set_handler(move || {
@@ -21,6 +31,18 @@ fn file_client(remote_addr: Ipv4Addr, remote_port: u16, filepath: &str) -> Resul
     })?;
     let input = fs::read(filepath)?;
     tcp.send(sock_id, &input)?;
+    // 送信し終えたことをFINで知らせ，サーバーからの応答を受信する
+    tcp.shutdown(sock_id, Shutdown::Write)?;
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        let nbytes = tcp.recv(sock_id, &mut buffer)?;
+        if nbytes == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..nbytes]);
+    }
+    print!("{}", str::from_utf8(&response)?);
     tcp.close(sock_id).unwrap();
     Ok(())
 }
@@ -1,28 +1,52 @@
 use anyhow::Result;
-use std::{env, fs, net::Ipv4Addr, str};
+use std::{env, fs, net::Ipv4Addr, str, thread, time::Duration};
 use toytcp::tcp::TCP;
 
+const BACKLOG: usize = 16;
+
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     let addr: Ipv4Addr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
     let savepath: &str = &args[3];
-    file_server(addr, port, savepath)?;
+    // 受信を始める前に待機する秒数．受信側が読み込みを止めた状況を再現する
+    let stall_secs: u64 = match args.get(4) {
+        Some(s) => s.parse()?,
+        None => 0,
+    };
+    let congestion_control = args.get(5).map(|s| s.as_str());
+    file_server(addr, port, savepath, stall_secs, congestion_control)?;
     Ok(())
 }
 
-fn file_server(local_addr: Ipv4Addr, local_port: u16, savepath: &str) -> Result<()> {
+fn file_server(
+    local_addr: Ipv4Addr,
+    local_port: u16,
+    savepath: &str,
+    stall_secs: u64,
+    congestion_control: Option<&str>,
+) -> Result<()> {
     let tcp = TCP::new();
-    let listening_socket = tcp.listen(local_addr, local_port)?;
+    // ECNを要求するクライアントとの接続ではECNを使う
+    tcp.set_ecn(true);
+    let listening_socket = tcp.listen(local_addr, local_port, BACKLOG)?;
+    if let Some(name) = congestion_control {
+        // DCTCPでは受信側もCEマークの返し方が変わるので，両端で指定する
+        tcp.set_congestion_control(listening_socket, name)?;
+    }
     dbg!("listening...");
     loop {
         let connected_socket = tcp.accept(listening_socket)?;
         dbg!("accepted!", connected_socket.1, connected_socket.3);
+        thread::sleep(Duration::from_secs(stall_secs));
         let mut v = Vec::new();
         let mut buffer = [0u8; 2000];
         loop {
             let nbytes = tcp.recv(connected_socket, &mut buffer).unwrap();
             if nbytes == 0 {
+                // クライアントは送信側だけを閉じているので，応答を返してから閉じる
+                let response = format!("received {} bytes\n", v.len());
+                tcp.send(connected_socket, response.as_bytes()).unwrap();
                 dbg!("closing connection...");
                 tcp.close(connected_socket).unwrap();
                 break;
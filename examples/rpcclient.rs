@@ -0,0 +1,28 @@
+use anyhow::Result;
+use std::{env, net::Ipv4Addr, str};
+use toytcp::tcp::TCP;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let addr: Ipv4Addr = args[1].parse()?;
+    let port: u16 = args[2].parse()?;
+    let request: &str = &args[3];
+    let count: usize = args.get(4).map_or(Ok(3), |s| s.parse())?;
+    rpc_client(addr, port, request, count)?;
+    Ok(())
+}
+
+/// 1つの要求を送って応答を受け取るたびに接続し直す．
+/// 最初の接続でサーバーのcookieを受け取り，以降は要求をSYNに載せて送る
+fn rpc_client(remote_addr: Ipv4Addr, remote_port: u16, request: &str, count: usize) -> Result<()> {
+    let tcp = TCP::new();
+    for _ in 0..count {
+        let request = format!("{}\n", request);
+        let sock_id = tcp.connect_with_data(remote_addr, remote_port, request.as_bytes())?;
+        let mut buffer = vec![0; 1500];
+        let n = tcp.recv(sock_id, &mut buffer)?;
+        print!("> {}", str::from_utf8(&buffer[..n])?);
+        tcp.close(sock_id)?;
+    }
+    Ok(())
+}
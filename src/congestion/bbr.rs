@@ -0,0 +1,346 @@
+use super::{AckSample, CongestionControl};
+use crate::socket::INITIAL_CWND;
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// スタートアップで1RTTごとに送信レートを倍にするためのゲイン 2/ln(2)
+const HIGH_GAIN: f64 = 2.885;
+// ProbeBWで8RTTごとに繰り返すペーシングゲインのサイクル
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const PROBE_BW_CWND_GAIN: f64 = 2.0;
+// ボトルネック帯域は直近10ラウンドの最大値とする
+const BTL_BW_FILTER_ROUNDS: u64 = 10;
+// 最小RTTがこの時間更新されなければProbeRTTに入る
+const MIN_RTT_FILTER_LEN: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+// 帯域が3ラウンド続けて25%以上伸びなければパイプが埋まったとみなす
+const FULL_BW_THRESHOLD: f64 = 1.25;
+const FULL_BW_COUNT: u8 = 3;
+const MIN_CWND_SEGMENTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BbrState {
+    Startup,
+    Drain,
+    ProbeBW,
+    ProbeRTT,
+}
+
+/// BBR(v1)．配送レートの計測値からボトルネック帯域と最小RTTを推定し，
+/// その積(BDP)を元に輻輳ウィンドウとペーシングレートを決める．
+/// 損失は輻輳の信号としては使わないため，ランダムロスのある経路でもウィンドウが縮まない
+pub struct Bbr {
+    state: BbrState,
+    cwnd: u32,
+    prior_cwnd: u32, // 損失からの回復前のウィンドウ
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    btl_bw_samples: VecDeque<(u64, u64)>, // (ラウンド, 配送レート)
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Instant,
+    round_count: u64,
+    next_round_delivered: u64,
+    round_start: bool,
+    full_bw: u64,
+    full_bw_count: u8,
+    filled_pipe: bool,
+    cycle_index: usize,
+    cycle_stamp: Instant,
+    probe_rtt_done_stamp: Option<Instant>,
+    in_recovery: bool,
+}
+
+impl Bbr {
+    pub fn new() -> Self {
+        Self {
+            state: BbrState::Startup,
+            cwnd: INITIAL_CWND,
+            prior_cwnd: INITIAL_CWND,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            btl_bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: Instant::now(),
+            round_count: 0,
+            next_round_delivered: 0,
+            round_start: false,
+            full_bw: 0,
+            full_bw_count: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_stamp: Instant::now(),
+            probe_rtt_done_stamp: None,
+            in_recovery: false,
+        }
+    }
+
+    /// ボトルネック帯域の推定値(バイト/秒)
+    fn btl_bw(&self) -> u64 {
+        self.btl_bw_samples
+            .iter()
+            .map(|(_, bw)| *bw)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// 推定したBDPにゲインを掛けたウィンドウ
+    fn target_cwnd(&self, gain: f64, mss: u32) -> u32 {
+        match self.min_rtt {
+            Some(rtt) if self.btl_bw() > 0 => {
+                let bdp = self.btl_bw() as f64 * rtt.as_secs_f64();
+                cmp::max((bdp * gain) as u32, MIN_CWND_SEGMENTS * mss)
+            }
+            _ => INITIAL_CWND,
+        }
+    }
+
+    fn update_round(&mut self, sample: &AckSample) {
+        self.round_start = false;
+        if sample.prior_delivered >= self.next_round_delivered {
+            self.next_round_delivered = sample.delivered;
+            self.round_count += 1;
+            self.round_start = true;
+        }
+    }
+
+    fn update_btl_bw(&mut self, sample: &AckSample) {
+        if let Some(rate) = sample.delivery_rate {
+            let round = self.round_count;
+            self.btl_bw_samples.push_back((round, rate));
+            while let Some((r, _)) = self.btl_bw_samples.front() {
+                if *r + BTL_BW_FILTER_ROUNDS > round {
+                    break;
+                }
+                self.btl_bw_samples.pop_front();
+            }
+        }
+    }
+
+    fn update_min_rtt(&mut self, sample: &AckSample, now: Instant) {
+        let expired = now.duration_since(self.min_rtt_stamp) > MIN_RTT_FILTER_LEN;
+        if let Some(rtt) = sample.rtt {
+            if expired || self.min_rtt.is_none() || Some(rtt) <= self.min_rtt {
+                self.min_rtt = Some(rtt);
+                self.min_rtt_stamp = now;
+            }
+        }
+        if expired && self.state != BbrState::ProbeRTT {
+            // 最小RTTを測り直すためにキューを空にする
+            dbg!("bbr: enter probe_rtt");
+            self.state = BbrState::ProbeRTT;
+            self.pacing_gain = 1.0;
+            self.cwnd_gain = 1.0;
+            self.prior_cwnd = self.cwnd;
+            self.probe_rtt_done_stamp = None;
+        }
+    }
+
+    fn check_full_pipe(&mut self) {
+        if self.filled_pipe || !self.round_start {
+            return;
+        }
+        let bw = self.btl_bw();
+        if bw as f64 >= self.full_bw as f64 * FULL_BW_THRESHOLD {
+            self.full_bw = bw;
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        if self.full_bw_count >= FULL_BW_COUNT {
+            self.filled_pipe = true;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.state = BbrState::ProbeBW;
+        self.cwnd_gain = PROBE_BW_CWND_GAIN;
+        // 0.75のフェーズ以外からランダムに始める
+        self.cycle_index = rand::random::<usize>() % (PACING_GAIN_CYCLE.len() - 1);
+        if self.cycle_index >= 1 {
+            self.cycle_index += 1;
+        }
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        self.cycle_stamp = now;
+    }
+
+    fn update_state(&mut self, sample: &AckSample, now: Instant) {
+        match self.state {
+            BbrState::Startup if self.filled_pipe => {
+                dbg!("bbr: enter drain", self.btl_bw());
+                self.state = BbrState::Drain;
+                self.pacing_gain = 1.0 / HIGH_GAIN;
+                self.cwnd_gain = HIGH_GAIN;
+            }
+            BbrState::Drain if sample.flight_size <= self.target_cwnd(1.0, sample.mss) => {
+                dbg!("bbr: enter probe_bw");
+                self.enter_probe_bw(now);
+            }
+            BbrState::ProbeBW => {
+                // 1RTT経過する毎にゲインサイクルを進める
+                let rtt = self.min_rtt.unwrap_or_default();
+                if now.duration_since(self.cycle_stamp) > rtt {
+                    self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+                    self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+                    self.cycle_stamp = now;
+                }
+            }
+            BbrState::ProbeRTT => match self.probe_rtt_done_stamp {
+                None if sample.flight_size <= MIN_CWND_SEGMENTS * sample.mss => {
+                    self.probe_rtt_done_stamp = Some(now + PROBE_RTT_DURATION);
+                }
+                Some(done) if now >= done => {
+                    self.min_rtt_stamp = now;
+                    self.cwnd = cmp::max(self.cwnd, self.prior_cwnd);
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.state = BbrState::Startup;
+                        self.pacing_gain = HIGH_GAIN;
+                        self.cwnd_gain = HIGH_GAIN;
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn update_cwnd(&mut self, sample: &AckSample) {
+        if self.in_recovery && !sample.in_recovery {
+            // 回復が終わったら損失前のウィンドウに戻す
+            self.cwnd = cmp::max(self.cwnd, self.prior_cwnd);
+        }
+        self.in_recovery = sample.in_recovery;
+        let min_cwnd = MIN_CWND_SEGMENTS * sample.mss;
+        if self.state == BbrState::ProbeRTT {
+            self.cwnd = cmp::min(self.cwnd, min_cwnd);
+            return;
+        }
+        let target = self.target_cwnd(self.cwnd_gain, sample.mss);
+        if self.filled_pipe {
+            self.cwnd = cmp::min(self.cwnd + sample.acked, target);
+        } else if self.cwnd < target || sample.delivered < INITIAL_CWND as u64 {
+            self.cwnd += sample.acked;
+        }
+        self.cwnd = cmp::max(self.cwnd, min_cwnd);
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn name(&self) -> &'static str {
+        "bbr"
+    }
+
+    fn on_ack(&mut self, sample: &AckSample) {
+        let now = Instant::now();
+        self.update_round(sample);
+        self.update_btl_bw(sample);
+        self.check_full_pipe();
+        self.update_min_rtt(sample, now);
+        self.update_state(sample, now);
+        self.update_cwnd(sample);
+    }
+
+    fn on_loss(&mut self, flight_size: u32, mss: u32) {
+        // モデルは変えずに，回復中は送信中のバイト数までウィンドウを抑える(packet conservation)
+        self.prior_cwnd = cmp::max(self.prior_cwnd, self.cwnd);
+        self.cwnd = cmp::max(flight_size, MIN_CWND_SEGMENTS * mss);
+        self.in_recovery = true;
+    }
+
+    fn on_rto(&mut self, _flight_size: u32, mss: u32) {
+        self.prior_cwnd = cmp::max(self.prior_cwnd, self.cwnd);
+        self.cwnd = mss;
+        self.in_recovery = true;
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        u32::MAX
+    }
+
+    fn set_window(&mut self, cwnd: u32, _ssthresh: u32) {
+        // ボトルネック帯域を計測するまではこのウィンドウで送る
+        self.cwnd = cwnd;
+        self.prior_cwnd = cwnd;
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        match self.btl_bw() {
+            0 => None,
+            bw => Some((bw as f64 * self.pacing_gain) as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::ack_sample;
+    use super::*;
+    use crate::socket::MSS;
+
+    const RTT: Duration = Duration::from_millis(10);
+
+    /// 毎回新しいラウンドを始めるACK．rateは配送レートの計測値
+    fn sample(round: u64, rate: u64, flight_size: u32) -> AckSample {
+        let delivered = round * 10 * MSS as u64;
+        AckSample {
+            rtt: Some(RTT),
+            delivery_rate: Some(rate),
+            delivered,
+            prior_delivered: delivered,
+            flight_size,
+            ..ack_sample(MSS as u32)
+        }
+    }
+
+    #[test]
+    fn test_startup_drain_probe_bw() {
+        let mut bbr = Bbr::new();
+        // 帯域が伸びている間はスタートアップを続ける
+        let mut round = 1;
+        for &rate in &[1_000_000, 2_000_000, 4_000_000] {
+            bbr.on_ack(&sample(round, rate, 0));
+            round += 1;
+            assert_eq!(bbr.state, BbrState::Startup);
+        }
+        assert_eq!(bbr.pacing_rate(), Some((4_000_000.0 * HIGH_GAIN) as u64));
+        assert_eq!(bbr.min_rtt, Some(RTT));
+
+        // 3ラウンド伸びなければパイプが埋まったとみなし，溜まったキューを抜く
+        for _ in 0..FULL_BW_COUNT {
+            bbr.on_ack(&sample(round, 4_000_000, 100 * MSS as u32));
+            round += 1;
+        }
+        assert!(bbr.filled_pipe);
+        assert_eq!(bbr.state, BbrState::Drain);
+        assert_eq!(bbr.pacing_rate(), Some((4_000_000.0 / HIGH_GAIN) as u64));
+
+        // 送信中のバイト数がBDP以下になったらProbeBWに入る
+        bbr.on_ack(&sample(round, 4_000_000, 0));
+        assert_eq!(bbr.state, BbrState::ProbeBW);
+        assert_ne!(bbr.pacing_gain, 0.75);
+        let bdp = 4_000_000.0 * RTT.as_secs_f64();
+        assert!(bbr.cwnd() <= (bdp * PROBE_BW_CWND_GAIN) as u32);
+    }
+
+    #[test]
+    fn test_loss_recovery_restores_cwnd() {
+        let mut bbr = Bbr::new();
+        bbr.cwnd = 20 * MSS as u32;
+        // 回復中は送信中のバイト数までウィンドウを抑え，回復が終わったら戻す
+        bbr.on_loss(5 * MSS as u32, MSS as u32);
+        assert_eq!(bbr.cwnd(), 5 * MSS as u32);
+        bbr.on_ack(&AckSample {
+            in_recovery: true,
+            ..sample(1, 1_000_000, 5 * MSS as u32)
+        });
+        assert!(bbr.cwnd() < 20 * MSS as u32);
+        bbr.on_ack(&sample(2, 1_000_000, 5 * MSS as u32));
+        assert!(bbr.cwnd() >= 20 * MSS as u32);
+    }
+}
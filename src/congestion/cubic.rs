@@ -0,0 +1,203 @@
+use super::{AckSample, CongestionControl};
+use crate::socket::INITIAL_CWND;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+// Renoと同等の平均ウィンドウとなるための加算係数 3*(1-β)/(1+β)
+const CUBIC_ALPHA: f64 = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+
+/// RFC9438のCUBIC．ウィンドウの計算はセグメント単位の浮動小数点で行う
+pub struct Cubic {
+    cwnd: u32,
+    ssthresh: u32,
+    w_max: f64,                   // 直前の輻輳イベント時のウィンドウ(セグメント)
+    k: f64,                       // w_maxに到達するまでの時間(秒)
+    origin: f64,                  // 3次関数の原点となるウィンドウ(セグメント)
+    w_est: f64,                   // Renoだった場合のウィンドウの推定値(セグメント)
+    epoch_start: Option<Instant>, // 輻輳回避を開始した時刻
+    min_rtt: Option<Duration>,
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        Self {
+            cwnd: INITIAL_CWND,
+            ssthresh: u32::MAX,
+            w_max: 0.0,
+            k: 0.0,
+            origin: 0.0,
+            w_est: 0.0,
+            epoch_start: None,
+            min_rtt: None,
+        }
+    }
+
+    /// セグメント数で表したウィンドウ
+    fn segments(&self, mss: u32) -> f64 {
+        self.cwnd as f64 / mss as f64
+    }
+
+    /// 時刻tにおけるCUBIC関数の値 W_cubic(t) = C*(t-K)^3 + W_max
+    fn w_cubic(&self, t: f64) -> f64 {
+        CUBIC_C * (t - self.k).powi(3) + self.origin
+    }
+
+    /// 輻輳イベントが起きた時にw_maxを更新し，ssthreshを返す
+    fn reduce(&mut self, mss: u32) -> u32 {
+        let cwnd = self.segments(mss);
+        self.w_max = if cwnd < self.w_max {
+            // fast convergence
+            cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.epoch_start = None;
+        cmp::max((self.cwnd as f64 * CUBIC_BETA) as u32, 2 * mss)
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "cubic"
+    }
+
+    fn on_ack(&mut self, sample: &AckSample) {
+        if let Some(rtt) = sample.rtt {
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| cmp::min(min, rtt)));
+        }
+        if sample.in_recovery {
+            // 高速回復中のウィンドウはtcp.rs側で管理する
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            // スロースタート
+            self.cwnd += cmp::min(sample.acked, sample.mss);
+            return;
+        }
+        let now = Instant::now();
+        let cwnd = self.segments(sample.mss);
+        let epoch_start = match self.epoch_start {
+            Some(start) => start,
+            None => {
+                // 輻輳回避の開始
+                if cwnd < self.w_max {
+                    self.k = ((self.w_max - cwnd) / CUBIC_C).cbrt();
+                    self.origin = self.w_max;
+                } else {
+                    self.k = 0.0;
+                    self.origin = cwnd;
+                }
+                self.w_est = cwnd;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+        let rtt = self.min_rtt.unwrap_or_default().as_secs_f64();
+        let t = now.duration_since(epoch_start).as_secs_f64();
+        // 1RTT後のウィンドウを目標にする．ただし1RTTで1.5倍を超えては増やさない
+        let target = self.w_cubic(t + rtt).max(cwnd).min(cwnd * 1.5);
+        let acked = sample.acked as f64 / sample.mss as f64;
+        // Renoと同等以上の速度は保証する(Reno-friendly region)
+        self.w_est += CUBIC_ALPHA * acked / cwnd;
+        let next = if self.w_est > target {
+            self.w_est
+        } else {
+            cwnd + (target - cwnd) * acked / cwnd
+        };
+        self.cwnd = cmp::max(self.cwnd, (next * sample.mss as f64) as u32);
+    }
+
+    fn on_loss(&mut self, _flight_size: u32, mss: u32) {
+        self.ssthresh = self.reduce(mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, _flight_size: u32, mss: u32) {
+        self.ssthresh = self.reduce(mss);
+        self.cwnd = mss;
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    fn set_window(&mut self, cwnd: u32, ssthresh: u32) {
+        self.cwnd = cwnd;
+        self.ssthresh = ssthresh;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::ack_sample;
+    use super::*;
+    use crate::socket::MSS;
+
+    const MSS32: u32 = MSS as u32;
+
+    #[test]
+    fn test_loss_reduces_by_beta() {
+        let mut cubic = Cubic::new();
+        cubic.cwnd = 100 * MSS32;
+        cubic.on_loss(100 * MSS32, MSS32);
+        assert_eq!(cubic.ssthresh(), 70 * MSS32);
+        assert_eq!(cubic.cwnd(), 70 * MSS32);
+        assert_eq!(cubic.w_max, 100.0);
+        // w_maxに届く前の損失では，他のフローに帯域を譲るためにw_maxを下げる(fast convergence)
+        cubic.on_loss(70 * MSS32, MSS32);
+        assert_eq!(cubic.w_max, 70.0 * (1.0 + CUBIC_BETA) / 2.0);
+        assert_eq!(cubic.cwnd(), 49 * MSS32);
+    }
+
+    #[test]
+    fn test_concave_growth() {
+        let mut cubic = Cubic::new();
+        cubic.cwnd = 100 * MSS32;
+        cubic.on_loss(100 * MSS32, MSS32);
+        // 損失直後はw_maxより十分小さいので，1RTTで1MSS程度しか増やさない
+        for _ in 0..70 {
+            cubic.on_ack(&AckSample {
+                rtt: Some(Duration::from_millis(10)),
+                ..ack_sample(MSS32)
+            });
+        }
+        assert!(cubic.cwnd() > 70 * MSS32);
+        assert!(cubic.cwnd() < 71 * MSS32);
+        assert!(cubic.k > 0.0);
+        assert_eq!(cubic.origin, 100.0);
+    }
+
+    #[test]
+    fn test_rto() {
+        let mut cubic = Cubic::new();
+        cubic.cwnd = 10 * MSS32;
+        cubic.on_rto(10 * MSS32, MSS32);
+        assert_eq!(cubic.ssthresh(), 7 * MSS32);
+        assert_eq!(cubic.cwnd(), MSS32);
+        // ssthreshまではスロースタート
+        cubic.on_ack(&ack_sample(MSS32));
+        assert_eq!(cubic.cwnd(), 2 * MSS32);
+    }
+
+    #[test]
+    fn test_connection_mss() {
+        let mss = 536;
+        let mut cubic = Cubic::new();
+        cubic.cwnd = 10 * mss;
+        cubic.on_rto(10 * mss, mss);
+        assert_eq!(cubic.ssthresh(), (10.0 * mss as f64 * CUBIC_BETA) as u32);
+        assert_eq!(cubic.cwnd(), mss);
+        // スロースタートではackされたバイト数を相手のMSSまでしか増やさない
+        cubic.on_ack(&AckSample {
+            mss,
+            ..ack_sample(MSS32)
+        });
+        assert_eq!(cubic.cwnd(), 2 * mss);
+    }
+}
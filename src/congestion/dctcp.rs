@@ -0,0 +1,162 @@
+use super::{AckSample, CongestionControl};
+use crate::socket::INITIAL_CWND;
+use std::cmp;
+
+// alphaを更新する時の新しい観測値の重み(RFC8257のg)
+const DCTCP_G: f64 = 1.0 / 16.0;
+
+/// RFC8257のDCTCP．ウィンドウ毎にCEマークの付いたバイトの割合からalphaを推定し，
+/// ECEを受信した時は輻輳ウィンドウをalpha/2の割合だけ縮める．
+/// ウィンドウの増やし方と損失時の動作はRenoと同じ
+pub struct Dctcp {
+    cwnd: u32,
+    ssthresh: u32,
+    alpha: f64,        // CEマークの付いたバイトの割合の推定値
+    acked_bytes: u64,  // 観測中のウィンドウでackされたバイト数
+    marked_bytes: u64, // そのうちECEの立ったACKでackされたバイト数
+    window_end: u64,   // 観測中のウィンドウの終わりとなるdelivered
+}
+
+impl Dctcp {
+    pub fn new() -> Self {
+        Self {
+            cwnd: INITIAL_CWND,
+            ssthresh: u32::MAX,
+            // 最初の輻輳では半分に縮める
+            alpha: 1.0,
+            acked_bytes: 0,
+            marked_bytes: 0,
+            window_end: 0,
+        }
+    }
+
+    /// ackされたバイト数とECEを記録し，ウィンドウ分のACKを受信したらalphaを更新する
+    fn update_alpha(&mut self, sample: &AckSample) {
+        self.acked_bytes += sample.acked as u64;
+        if sample.ece {
+            self.marked_bytes += sample.acked as u64;
+        }
+        if sample.delivered < self.window_end {
+            return;
+        }
+        if self.acked_bytes > 0 {
+            let fraction = self.marked_bytes as f64 / self.acked_bytes as f64;
+            self.alpha = (1.0 - DCTCP_G) * self.alpha + DCTCP_G * fraction;
+            dbg!("dctcp alpha", self.alpha);
+        }
+        self.acked_bytes = 0;
+        self.marked_bytes = 0;
+        self.window_end = sample.delivered + sample.flight_size as u64;
+    }
+}
+
+impl CongestionControl for Dctcp {
+    fn name(&self) -> &'static str {
+        "dctcp"
+    }
+
+    fn on_ack(&mut self, sample: &AckSample) {
+        self.update_alpha(sample);
+        if sample.in_recovery {
+            // 高速回復中のウィンドウはtcp.rs側で管理する
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            // スロースタート
+            self.cwnd += cmp::min(sample.acked, sample.mss);
+        } else {
+            // 輻輳回避
+            self.cwnd += cmp::max(1, sample.mss * sample.mss / self.cwnd);
+        }
+    }
+
+    fn on_loss(&mut self, flight_size: u32, mss: u32) {
+        self.ssthresh = cmp::max(flight_size / 2, 2 * mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, flight_size: u32, mss: u32) {
+        self.ssthresh = cmp::max(flight_size / 2, 2 * mss);
+        self.cwnd = mss;
+    }
+
+    fn on_ecn(&mut self, _flight_size: u32, mss: u32) {
+        let reduced = self.cwnd as f64 * (1.0 - self.alpha / 2.0);
+        self.cwnd = cmp::max(reduced as u32, 2 * mss);
+        self.ssthresh = self.cwnd;
+    }
+
+    fn needs_ecn(&self) -> bool {
+        true
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    fn set_window(&mut self, cwnd: u32, ssthresh: u32) {
+        self.cwnd = cwnd;
+        self.ssthresh = ssthresh;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::ack_sample;
+    use super::*;
+    use crate::socket::MSS;
+
+    const MSS32: u32 = MSS as u32;
+
+    /// deliveredまでackされ，flight_sizeが送信中のACK
+    fn sample(delivered: u64, flight_size: u32, ece: bool) -> AckSample {
+        AckSample {
+            delivered,
+            flight_size,
+            ece,
+            ..ack_sample(MSS32)
+        }
+    }
+
+    #[test]
+    fn test_first_ecn_halves_cwnd() {
+        let mut dctcp = Dctcp::new();
+        dctcp.cwnd = 20 * MSS32;
+        dctcp.on_ecn(20 * MSS32, MSS32);
+        assert_eq!(dctcp.cwnd(), 10 * MSS32);
+        assert_eq!(dctcp.ssthresh(), 10 * MSS32);
+        // 2MSSより小さくしない
+        dctcp.cwnd = 3 * MSS32;
+        dctcp.on_ecn(3 * MSS32, MSS32);
+        assert_eq!(dctcp.cwnd(), 2 * MSS32);
+    }
+
+    #[test]
+    fn test_alpha_per_window() {
+        let mut dctcp = Dctcp::new();
+        // 最初のACKで観測するウィンドウを決める．CEマークがなければalphaは減る
+        dctcp.on_ack(&sample(MSS as u64, 4 * MSS32, false));
+        assert_eq!(dctcp.alpha, 1.0 - DCTCP_G);
+        assert_eq!(dctcp.window_end, 5 * MSS as u64);
+        // ウィンドウ分のACKを受信するまではalphaを更新しない
+        for i in 2..5 {
+            dctcp.on_ack(&sample(i * MSS as u64, 4 * MSS32, i % 2 == 0));
+            assert_eq!(dctcp.alpha, 1.0 - DCTCP_G);
+        }
+        // 4つのうち半分にCEマークが付いていた
+        dctcp.on_ack(&sample(5 * MSS as u64, 4 * MSS32, false));
+        let alpha = (1.0 - DCTCP_G) * (1.0 - DCTCP_G) + DCTCP_G * 0.5;
+        assert!((dctcp.alpha - alpha).abs() < 1e-9);
+
+        dctcp.cwnd = 20 * MSS32;
+        dctcp.on_ecn(20 * MSS32, MSS32);
+        assert_eq!(
+            dctcp.cwnd(),
+            (20.0 * MSS as f64 * (1.0 - alpha / 2.0)) as u32
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! 輻輳制御アルゴリズム．
+//! ソケットごとに`CongestionControl`を実装したアルゴリズムを1つ持ち，
+//! tcp.rsはACK受信や損失検知のタイミングでそのコールバックを呼び出す．
+//! 新しいアルゴリズムはこのモジュールにサブモジュールを追加し，`from_name`に登録する．
+mod bbr;
+mod cubic;
+mod dctcp;
+mod reno;
+
+pub use bbr::Bbr;
+pub use cubic::Cubic;
+pub use dctcp::Dctcp;
+pub use reno::Reno;
+
+use anyhow::Result;
+use std::time::Duration;
+
+pub const DEFAULT_CONGESTION_CONTROL: &str = "reno";
+
+/// ACKを受信した時に輻輳制御アルゴリズムに渡す情報
+#[derive(Clone, Debug)]
+pub struct AckSample {
+    pub acked: u32,                 // 新たにackされたバイト数
+    pub flight_size: u32,           // ack処理後の送信済み未ackのバイト数
+    pub rtt: Option<Duration>,      // 再送していないセグメントから計測したRTT
+    pub delivery_rate: Option<u64>, // 配送レートの計測値(バイト/秒)
+    pub delivered: u64,             // これまでにackされたバイト数
+    pub prior_delivered: u64,       // 計測に使ったセグメントの送信時点のdelivered
+    pub in_recovery: bool,          // 高速回復中かどうか
+    pub ece: bool,                  // ACKにECEが立っていたか
+    pub mss: u32,                   // 送信するセグメントの最大サイズ(Socket::mss)
+}
+
+/// 輻輳制御アルゴリズムのインタフェース．LinuxのTCP_CONGESTIONのようにソケットごとに選択できる．
+/// ウィンドウはMSS定数ではなく，引数で渡される接続ごとのMSSを単位に増減させる
+pub trait CongestionControl: Send + Sync {
+    /// アルゴリズム名
+    fn name(&self) -> &'static str;
+    /// 新しいデータをackするACKを受信した
+    fn on_ack(&mut self, sample: &AckSample);
+    /// 重複ACKによって損失を検知し，高速回復に入る
+    fn on_loss(&mut self, flight_size: u32, mss: u32);
+    /// 再送タイムアウトが発生した
+    fn on_rto(&mut self, flight_size: u32, mss: u32);
+    /// ECEを受信して輻輳を検知した(RFC3168)．1ウィンドウに1回だけ呼ばれる．
+    /// デフォルトでは損失と同じように扱う
+    fn on_ecn(&mut self, flight_size: u32, mss: u32) {
+        self.on_loss(flight_size, mss);
+    }
+    /// 輻輳ウィンドウ(バイト)
+    fn cwnd(&self) -> u32;
+    /// スロースタート閾値(バイト)
+    fn ssthresh(&self) -> u32;
+    /// 輻輳ウィンドウとスロースタート閾値を設定する．接続の途中でアルゴリズムを切り替えた時に，
+    /// それまでのアルゴリズムの値を引き継ぐために使う
+    fn set_window(&mut self, cwnd: u32, ssthresh: u32);
+    /// ECNが必須のアルゴリズムかどうか(LinuxのTCP_CONG_NEEDS_ECN)．trueなら
+    /// リスニングソケットはECNを要求するSYNに必ず応じ，受信側はCEマークの有無をそのままECEで返す
+    fn needs_ecn(&self) -> bool {
+        false
+    }
+    /// ペーシングレート(バイト/秒)．Noneならペーシングしない
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// 名前から輻輳制御アルゴリズムを生成する
+pub fn from_name(name: &str) -> Result<Box<dyn CongestionControl>> {
+    match name {
+        "reno" => Ok(Box::new(Reno::new())),
+        "cubic" => Ok(Box::new(Cubic::new())),
+        "bbr" => Ok(Box::new(Bbr::new())),
+        "dctcp" => Ok(Box::new(Dctcp::new())),
+        _ => anyhow::bail!("unknown congestion control: {}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::MSS;
+
+    /// ackedバイトをackした時の計測値．その他の値は各テストで上書きする
+    pub fn ack_sample(acked: u32) -> AckSample {
+        AckSample {
+            acked,
+            flight_size: 0,
+            rtt: None,
+            delivery_rate: None,
+            delivered: 0,
+            prior_delivered: 0,
+            in_recovery: false,
+            ece: false,
+            mss: MSS as u32,
+        }
+    }
+
+    #[test]
+    fn test_from_name() {
+        for name in &["reno", "cubic", "bbr", "dctcp"] {
+            assert_eq!(from_name(name).unwrap().name(), *name);
+        }
+        assert!(from_name("vegas").is_err());
+    }
+}
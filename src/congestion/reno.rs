@@ -0,0 +1,119 @@
+use super::{AckSample, CongestionControl};
+use crate::socket::INITIAL_CWND;
+use std::cmp;
+
+/// RFC5681のスロースタートと輻輳回避
+pub struct Reno {
+    cwnd: u32,
+    ssthresh: u32,
+}
+
+impl Reno {
+    pub fn new() -> Self {
+        Self {
+            cwnd: INITIAL_CWND,
+            ssthresh: u32::MAX,
+        }
+    }
+}
+
+impl CongestionControl for Reno {
+    fn name(&self) -> &'static str {
+        "reno"
+    }
+
+    fn on_ack(&mut self, sample: &AckSample) {
+        if sample.in_recovery {
+            // 高速回復中のウィンドウはtcp.rs側で管理する
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            // スロースタート
+            self.cwnd += cmp::min(sample.acked, sample.mss);
+        } else {
+            // 輻輳回避
+            self.cwnd += cmp::max(1, sample.mss * sample.mss / self.cwnd);
+        }
+    }
+
+    fn on_loss(&mut self, flight_size: u32, mss: u32) {
+        self.ssthresh = cmp::max(flight_size / 2, 2 * mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, flight_size: u32, mss: u32) {
+        self.ssthresh = cmp::max(flight_size / 2, 2 * mss);
+        self.cwnd = mss;
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    fn set_window(&mut self, cwnd: u32, ssthresh: u32) {
+        self.cwnd = cwnd;
+        self.ssthresh = ssthresh;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::ack_sample;
+    use super::*;
+    use crate::socket::MSS;
+
+    const MSS32: u32 = MSS as u32;
+
+    #[test]
+    fn test_slow_start_and_congestion_avoidance() {
+        let mut reno = Reno::new();
+        // スロースタートではackされたバイト数(最大MSS)だけ増やす
+        reno.on_ack(&ack_sample(MSS32));
+        assert_eq!(reno.cwnd(), INITIAL_CWND + MSS32);
+        reno.on_ack(&ack_sample(3 * MSS32));
+        assert_eq!(reno.cwnd(), INITIAL_CWND + 2 * MSS32);
+
+        // 損失で送信中のバイト数の半分に縮め，以降は輻輳回避
+        reno.on_loss(10 * MSS32, MSS32);
+        assert_eq!(reno.ssthresh(), 5 * MSS32);
+        assert_eq!(reno.cwnd(), 5 * MSS32);
+        reno.on_ack(&ack_sample(MSS32));
+        assert_eq!(reno.cwnd(), 5 * MSS32 + MSS32 / 5);
+
+        // 高速回復中は変えない
+        let cwnd = reno.cwnd();
+        reno.on_ack(&AckSample {
+            in_recovery: true,
+            ..ack_sample(MSS32)
+        });
+        assert_eq!(reno.cwnd(), cwnd);
+    }
+
+    #[test]
+    fn test_rto() {
+        let mut reno = Reno::new();
+        reno.on_rto(2 * MSS32, MSS32);
+        // ssthreshは2MSSより小さくしない
+        assert_eq!(reno.ssthresh(), 2 * MSS32);
+        assert_eq!(reno.cwnd(), MSS32);
+    }
+
+    #[test]
+    fn test_connection_mss() {
+        // 相手のMSSが小さければ，ウィンドウもそのMSSを単位に増減させる
+        let mss = 536;
+        let mut reno = Reno::new();
+        reno.on_ack(&AckSample {
+            mss,
+            ..ack_sample(MSS32)
+        });
+        assert_eq!(reno.cwnd(), INITIAL_CWND + mss);
+        reno.on_rto(2 * mss, mss);
+        assert_eq!(reno.ssthresh(), 2 * mss);
+        assert_eq!(reno.cwnd(), mss);
+    }
+}
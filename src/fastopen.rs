@@ -0,0 +1,38 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::Ipv4Addr;
+
+/// サーバーが発行するcookieの長さ(バイト)
+pub const COOKIE_LEN: usize = 8;
+
+/// TCP Fast Open(RFC7413)のcookieを生成する．クライアントのアドレスと秘密の値のハッシュなので，
+/// 同じクライアントには同じcookieを返し，サーバーは状態を持たずに検証できる
+pub fn generate(secret: u64, client_addr: Ipv4Addr) -> [u8; COOKIE_LEN] {
+    let mut hasher = DefaultHasher::new();
+    secret.hash(&mut hasher);
+    client_addr.hash(&mut hasher);
+    hasher.finish().to_be_bytes()
+}
+
+/// SYNに載っていたcookieがこのクライアントに発行したものか検証する
+pub fn validate(secret: u64, client_addr: Ipv4Addr, cookie: &[u8]) -> bool {
+    cookie == generate(secret, client_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_and_validate() {
+        let client = Ipv4Addr::new(10, 0, 0, 1);
+        let cookie = generate(1, client);
+        assert_eq!(generate(1, client), cookie);
+        assert!(validate(1, client, &cookie));
+        // 別のクライアントや別の秘密の値のcookieは受け付けない
+        assert!(!validate(1, Ipv4Addr::new(10, 0, 0, 2), &cookie));
+        assert!(!validate(2, client, &cookie));
+        assert!(!validate(1, client, &cookie[..4]));
+        assert!(!validate(1, client, &[]));
+    }
+}
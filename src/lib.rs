@@ -1,4 +1,9 @@
+mod congestion;
+mod fastopen;
 mod packet;
+mod ring_buffer;
 mod socket;
+mod syncookie;
 pub mod tcp;
 mod tcpflags;
+mod tcpoptions;
@@ -1,9 +1,12 @@
 use crate::tcpflags;
+use crate::tcpoptions;
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
 use pnet::util;
 
+use std::cmp;
 use std::fmt::{self, Debug};
 use std::net::Ipv4Addr;
+use std::ops::Range;
 const TCP_HEADER_SIZE: usize = 20;
 
 #[derive(Clone)]
@@ -12,10 +15,16 @@ pub struct TCPPacket {
 }
 
 impl TCPPacket {
-    pub fn new(payload_len: usize) -> Self {
-        Self {
-            buffer: vec![0; TCP_HEADER_SIZE + payload_len],
-        }
+    /// オプションを4バイト境界までEND(0)で埋めて，その長さに合わせたデータオフセットを設定する
+    pub fn new(options: &[u8], payload_len: usize) -> Self {
+        debug_assert!(options.len() <= tcpoptions::MAX_OPTIONS_LEN);
+        let options_len = (options.len() + 3) & !3;
+        let mut packet = Self {
+            buffer: vec![0; TCP_HEADER_SIZE + options_len + payload_len],
+        };
+        packet.buffer[TCP_HEADER_SIZE..TCP_HEADER_SIZE + options.len()].copy_from_slice(options);
+        packet.set_data_offset(((TCP_HEADER_SIZE + options_len) / 4) as u8);
+        packet
     }
 
     pub fn get_src(&self) -> u16 {
@@ -56,6 +65,92 @@ impl TCPPacket {
         u16::from_be_bytes([self.buffer[16], self.buffer[17]])
     }
 
+    pub fn get_urgent_pointer(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[18], self.buffer[19]])
+    }
+
+    /// データオフセットから求めたオプションを含むヘッダの長さ
+    fn header_len(&self) -> usize {
+        let len = (self.buffer[12] >> 4) as usize * 4;
+        cmp::min(cmp::max(len, TCP_HEADER_SIZE), self.buffer.len())
+    }
+
+    pub fn options(&self) -> &[u8] {
+        &self.buffer[TCP_HEADER_SIZE..self.header_len()]
+    }
+
+    /// 指定した種類のオプションを探し，そのデータ部分(種類と長さを除く)を返す
+    pub fn get_option(&self, kind: u8) -> Option<&[u8]> {
+        let range = self.option_range(kind)?;
+        Some(&self.buffer[range])
+    }
+
+    /// 指定した種類のオプションのデータ部分がバッファのどこにあるかを返す
+    fn option_range(&self, kind: u8) -> Option<Range<usize>> {
+        let options = self.options();
+        let mut i = 0;
+        while i < options.len() {
+            match options[i] {
+                tcpoptions::END => break,
+                tcpoptions::NOP => i += 1,
+                k => {
+                    let len = *options.get(i + 1)? as usize;
+                    if len < 2 || i + len > options.len() {
+                        return None;
+                    }
+                    if k == kind {
+                        return Some(TCP_HEADER_SIZE + i + 2..TCP_HEADER_SIZE + i + len);
+                    }
+                    i += len;
+                }
+            }
+        }
+        None
+    }
+
+    /// RFC2385のMD5署名を計算する．疑似ヘッダ，オプションを除いたヘッダ(チェックサムは0とする)，
+    /// ペイロード，鍵の順にハッシュする
+    fn md5_digest(&self, src_addr: Ipv4Addr, dest_addr: Ipv4Addr, key: &[u8]) -> [u8; 16] {
+        let mut context = md5::Context::new();
+        context.consume(src_addr.octets());
+        context.consume(dest_addr.octets());
+        context.consume([0, IpNextHeaderProtocols::Tcp.0]);
+        context.consume((self.buffer.len() as u16).to_be_bytes());
+        let mut header = [0; TCP_HEADER_SIZE];
+        header.copy_from_slice(&self.buffer[..TCP_HEADER_SIZE]);
+        header[16..18].copy_from_slice(&[0, 0]);
+        context.consume(header);
+        context.consume(self.payload());
+        context.consume(key);
+        context.compute().0
+    }
+
+    /// MD5署名オプションに署名を書き込む．ヘッダとペイロードを設定した後，チェックサムの前に呼ぶ
+    pub fn sign_md5(&mut self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr, key: &[u8]) {
+        let digest = self.md5_digest(local_addr, remote_addr, key);
+        if let Some(range) = self.option_range(tcpoptions::MD5_SIGNATURE) {
+            if range.len() == digest.len() {
+                self.buffer[range].copy_from_slice(&digest);
+            }
+        }
+    }
+
+    /// 受信したセグメントのMD5署名を検証する．keyがNoneなら署名のないセグメントだけを正しいとする
+    pub fn is_correct_md5(
+        &self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        key: Option<&[u8]>,
+    ) -> bool {
+        match (self.get_option(tcpoptions::MD5_SIGNATURE), key) {
+            (None, None) => true,
+            (Some(signature), Some(key)) => {
+                signature == self.md5_digest(remote_addr, local_addr, key)
+            }
+            _ => false,
+        }
+    }
+
     pub fn set_src(&mut self, port: u16) {
         self.buffer[0..2].copy_from_slice(&port.to_be_bytes())
     }
@@ -88,9 +183,13 @@ impl TCPPacket {
         self.buffer[16..18].copy_from_slice(&checksum.to_be_bytes())
     }
 
+    pub fn set_urgent_pointer(&mut self, pointer: u16) {
+        self.buffer[18..20].copy_from_slice(&pointer.to_be_bytes())
+    }
+
     pub fn set_payload(&mut self, payload: &[u8]) {
-        self.buffer[TCP_HEADER_SIZE..TCP_HEADER_SIZE + payload.len() as usize]
-            .copy_from_slice(payload)
+        let header_len = self.header_len();
+        self.buffer[header_len..header_len + payload.len()].copy_from_slice(payload)
     }
 
     pub fn is_correct_checksum(&self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> bool {
@@ -112,7 +211,7 @@ impl Packet for TCPPacket {
     }
 
     fn payload(&self) -> &[u8] {
-        &self.buffer[TCP_HEADER_SIZE..]
+        &self.buffer[self.header_len()..]
     }
 }
 
@@ -140,3 +239,64 @@ impl<'a> From<TcpPacket<'a>> for TCPPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+    const KEY: &[u8] = b"secret";
+
+    /// クライアントからサーバへの署名付きセグメント
+    fn signed_packet(payload: &[u8]) -> TCPPacket {
+        let mut packet = TCPPacket::new(&tcpoptions::md5_signature(), payload.len());
+        packet.set_src(40000);
+        packet.set_dest(50000);
+        packet.set_seq(1000);
+        packet.set_ack(5000);
+        packet.set_flag(tcpflags::ACK | tcpflags::PSH);
+        packet.set_window_size(4380);
+        packet.set_payload(payload);
+        packet.sign_md5(CLIENT, SERVER, KEY);
+        packet
+    }
+
+    #[test]
+    fn test_md5_round_trip() {
+        let packet = signed_packet(b"hello");
+        assert_ne!(
+            packet.get_option(tcpoptions::MD5_SIGNATURE),
+            Some(&[0; 16][..])
+        );
+        // 受信側からは送信元と宛先が入れ替わる
+        assert!(packet.is_correct_md5(SERVER, CLIENT, Some(KEY)));
+        assert!(!packet.is_correct_md5(CLIENT, SERVER, Some(KEY)));
+        assert!(!packet.is_correct_md5(SERVER, CLIENT, Some(b"wrong")));
+    }
+
+    #[test]
+    fn test_md5_tampered() {
+        let mut packet = signed_packet(b"hello");
+        packet.set_payload(b"jello");
+        assert!(!packet.is_correct_md5(SERVER, CLIENT, Some(KEY)));
+
+        let mut packet = signed_packet(b"hello");
+        packet.set_seq(1001);
+        assert!(!packet.is_correct_md5(SERVER, CLIENT, Some(KEY)));
+
+        // チェックサムは署名の対象外
+        let mut packet = signed_packet(b"hello");
+        packet.set_checksum(0xffff);
+        assert!(packet.is_correct_md5(SERVER, CLIENT, Some(KEY)));
+    }
+
+    #[test]
+    fn test_md5_missing_or_unexpected() {
+        let mut packet = TCPPacket::new(&[], 0);
+        packet.set_flag(tcpflags::SYN);
+        assert!(packet.is_correct_md5(SERVER, CLIENT, None));
+        assert!(!packet.is_correct_md5(SERVER, CLIENT, Some(KEY)));
+        assert!(!signed_packet(&[]).is_correct_md5(SERVER, CLIENT, None));
+    }
+}
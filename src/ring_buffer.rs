@@ -0,0 +1,193 @@
+use std::cmp;
+
+/// 固定長のバイトのリングバッファ．送信バッファと受信バッファに使う
+#[derive(Debug)]
+pub struct RingBuffer {
+    buffer: Vec<u8>,
+    head: usize, // 先頭のデータの位置
+    len: usize,  // 格納しているバイト数
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: vec![0; capacity],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 空いているバイト数
+    pub fn free(&self) -> usize {
+        self.capacity() - self.len
+    }
+
+    /// 末尾にデータを追加する．空きが足りなければ入る分だけ追加し，追加したサイズを返す
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let size = cmp::min(data.len(), self.free());
+        let tail = self.index(self.head + self.len);
+        self.write(tail, &data[..size]);
+        self.len += size;
+        size
+    }
+
+    /// 末尾からoffsetバイト先の空き領域にdataを書き込み，書き込んだサイズを返す．
+    /// 格納しているバイト数は変えないので，書き込んだデータはcommitするまで読めない
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> usize {
+        if offset >= self.free() {
+            return 0;
+        }
+        let size = cmp::min(data.len(), self.free() - offset);
+        let pos = self.index(self.head + self.len + offset);
+        self.write(pos, &data[..size]);
+        size
+    }
+
+    /// write_atで末尾に書き込んだnバイトを格納済みのデータにする
+    pub fn commit(&mut self, n: usize) {
+        self.len += cmp::min(n, self.free());
+    }
+
+    /// 先頭からoffsetバイト目以降のデータをbufにコピーし，コピーしたサイズを返す
+    pub fn peek(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if offset >= self.len {
+            return 0;
+        }
+        let size = cmp::min(buf.len(), self.len - offset);
+        let start = self.index(self.head + offset);
+        let first = cmp::min(size, self.capacity() - start);
+        buf[..first].copy_from_slice(&self.buffer[start..start + first]);
+        buf[first..size].copy_from_slice(&self.buffer[..size - first]);
+        size
+    }
+
+    /// 先頭からnバイトを捨てる
+    pub fn consume(&mut self, n: usize) {
+        let n = cmp::min(n, self.len);
+        self.head = self.index(self.head + n);
+        self.len -= n;
+    }
+
+    /// 容量をcapacityまで広げる．格納済みのデータとwrite_atで書き込んだデータはそのまま残る
+    pub fn grow(&mut self, capacity: usize) {
+        let old = self.capacity();
+        if capacity <= old {
+            return;
+        }
+        let mut buffer = vec![0; capacity];
+        buffer[..old - self.head].copy_from_slice(&self.buffer[self.head..]);
+        buffer[old - self.head..old].copy_from_slice(&self.buffer[..self.head]);
+        self.buffer = buffer;
+        self.head = 0;
+    }
+
+    /// 先頭からの位置をバッファ内の位置に折り返す．容量0のバッファでも0除算しない
+    fn index(&self, pos: usize) -> usize {
+        pos % self.capacity().max(1)
+    }
+
+    /// バッファ内の位置posから(必要なら折り返して)dataを書き込む
+    fn write(&mut self, pos: usize, data: &[u8]) {
+        let first = cmp::min(data.len(), self.capacity() - pos);
+        self.buffer[pos..pos + first].copy_from_slice(&data[..first]);
+        self.buffer[..data.len() - first].copy_from_slice(&data[first..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 先頭から全てのデータを読み出す
+    fn contents(buffer: &RingBuffer) -> Vec<u8> {
+        let mut data = vec![0; buffer.len()];
+        buffer.peek(0, &mut data);
+        data
+    }
+
+    #[test]
+    fn test_push_and_consume_wraparound() {
+        let mut buffer = RingBuffer::new(8);
+        assert_eq!(buffer.push(b"abcdef"), 6);
+        buffer.consume(4);
+        // 末尾で折り返して書き込む
+        assert_eq!(buffer.push(b"ghijkl"), 6);
+        assert_eq!(contents(&buffer), b"efghijkl");
+        // 空きがなければ入る分だけ追加する
+        assert_eq!(buffer.push(b"m"), 0);
+        assert_eq!(buffer.free(), 0);
+
+        let mut data = [0; 3];
+        assert_eq!(buffer.peek(5, &mut data), 3);
+        assert_eq!(&data, b"jkl");
+        assert_eq!(buffer.peek(8, &mut data), 0);
+        buffer.consume(100);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_write_at_and_commit() {
+        let mut buffer = RingBuffer::new(8);
+        buffer.push(b"abcde");
+        buffer.consume(5);
+        // 順序が入れ替わって届いたデータは，commitするまで読めない
+        assert_eq!(buffer.write_at(3, b"defg"), 4);
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.write_at(0, b"abc"), 3);
+        buffer.commit(7);
+        assert_eq!(contents(&buffer), b"abcdefg");
+        // 空き領域を超える部分は書き込まない
+        assert_eq!(buffer.write_at(0, b"xyz"), 1);
+        assert_eq!(buffer.write_at(1, b"z"), 0);
+        buffer.commit(100);
+        assert_eq!(contents(&buffer), b"abcdefgx");
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut buffer = RingBuffer::new(4);
+        buffer.push(b"abcd");
+        buffer.consume(2);
+        buffer.push(b"ef");
+        buffer.grow(8);
+        assert_eq!(buffer.capacity(), 8);
+        assert_eq!(contents(&buffer), b"cdef");
+        // 広げる前にwrite_atで書き込んだデータも残る
+        let mut buffer = RingBuffer::new(4);
+        buffer.push(b"ab");
+        buffer.consume(1);
+        buffer.write_at(0, b"cd");
+        buffer.grow(6);
+        buffer.commit(2);
+        assert_eq!(contents(&buffer), b"bcd");
+        // 縮めはしない
+        buffer.grow(2);
+        assert_eq!(buffer.capacity(), 6);
+    }
+
+    #[test]
+    fn test_zero_capacity() {
+        let mut buffer = RingBuffer::new(0);
+        assert_eq!(buffer.push(b"a"), 0);
+        assert_eq!(buffer.write_at(0, b"a"), 0);
+        buffer.commit(1);
+        buffer.consume(1);
+        assert_eq!(buffer.peek(0, &mut [0; 1]), 0);
+        assert!(buffer.is_empty());
+        buffer.grow(2);
+        assert_eq!(buffer.push(b"abc"), 2);
+        assert_eq!(contents(&buffer), b"ab");
+    }
+}
@@ -1,15 +1,37 @@
+use crate::congestion::{self, CongestionControl};
 use crate::packet::TCPPacket;
+use crate::ring_buffer::RingBuffer;
 use crate::tcpflags;
+use crate::tcpoptions;
 use anyhow::{Context, Result};
 use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
 use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
 use pnet::util;
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
 use std::fmt::{self, Display};
 use std::net::{IpAddr, Ipv4Addr};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+use std::{io, mem};
 
 const SOCKET_BUFFER_SIZE: usize = 4380;
+const SEND_BUFFER_SIZE: usize = 65536;
+// 自動チューニングで広げる受信バッファの上限
+const MAX_RECV_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+// 受信ウィンドウを広告する時のシフト数．MAX_RECV_BUFFER_SIZEをヘッダのu16で表せるようにする
+const RECV_WINDOW_SHIFT: u8 = 7;
+pub const MSS: usize = 1460;
+const MIN_MSS: usize = 88; // 相手が広告したMSSが小さすぎても，これより小さくは分割しない(LinuxのTCP_MIN_MSS)
+pub const QUICK_ACK_SEGMENTS: u8 = 8;
+// 相手が広告したUser Timeoutを採用する時の下限と上限(RFC5482のL_LIMITとU_LIMIT)
+const MIN_REMOTE_USER_TIMEOUT: Duration = Duration::from_secs(100);
+const MAX_REMOTE_USER_TIMEOUT: Duration = Duration::from_secs(600);
+pub const INITIAL_CWND: u32 = 3 * MSS as u32; // RFC5681の初期ウィンドウ min(4*MSS, max(2*MSS, 4380))
+
+// IPヘッダのECNフィールドの値(RFC3168)
+pub const ECN_ECT0: u8 = 0b10;
+pub const ECN_CE: u8 = 0b11;
 
 /// (local_addr, remote_addr, local_port, remote_port)のタプルでソケットを識別する．
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
@@ -23,44 +45,196 @@ pub struct Socket {
     pub send_param: SendParam,
     pub recv_param: RecvParam,
     pub status: TcpStatus,
-    pub recv_buffer: Vec<u8>,
+    // 受信済みで未読のデータ．末尾はrecv_param.nextに対応する
+    pub recv_buffer: RingBuffer,
     pub retransmission_queue: VecDeque<RetransmissionQueueEntry>,
     pub connected_connection_queue: VecDeque<SockID>, // 接続済みソケットを保持するキュー．リスニングソケットのみ使用．
+    pub backlog: usize, // 半開きの接続と接続済みソケットのキューの上限．リスニングソケットのみ使用
+    pub half_open: usize, // SYNRCVDの接続の数．リスニングソケットのみ使用
+    pub counted_half_open: bool, // 生成元のリスニングソケットのhalf_openに数えられているか
     pub listening_socket: Option<SockID>, // 生成元のリスニングソケット．接続済みソケットのみ使用
+    #[cfg(test)]
+    pub sent: Vec<TCPPacket>, // 送信したセグメント．テストではこれを相手のソケットに届ける
     pub sender: TransportSender,
+    pub congestion: Box<dyn CongestionControl>,
+    pub nodelay: bool, // trueならNagleアルゴリズムを使わない
+    // 送信待ちと未ackのデータ．先頭はsend_param.unacked_seqに対応する
+    pub send_buffer: RingBuffer,
+    pub keepalive: Option<Keepalive>, // Noneならキープアライブしない
+    pub keepalive_probes: u8,         // 応答のないキープアライブプローブの数
+    pub last_received: SystemTime,    // 最後にセグメントを受信した時刻
+    pub error: Option<SocketError>,   // 接続が異常終了した理由
+    pub read_shutdown: bool,          // 受信側をshutdownしたか
+    pub linger: Option<Duration>,     // closeがFINのackを待つ時間．0ならRSTで切る(SO_LINGER)
+    pub oob_inline: bool,             // 緊急データを通常のデータと一緒に読むか(SO_OOBINLINE)
+    pub urgent_data: Option<u8>,      // 帯域外で受信してまだ読まれていない緊急データ
+    // closeされたか．closeされたTIME_WAITとFIN_WAIT2のソケットはタイマーが破棄する
+    pub released: bool,
+    // TIME_WAITかcloseされたFIN_WAIT2のソケットを破棄する時刻
+    pub close_deadline: Option<SystemTime>,
+    // User Timeout(RFC5482)
+    pub user_timeout: Option<Duration>, // 未ackのまま許容する時間
+    pub advertise_user_timeout: bool,   // オプションで相手に広告するか
+    pub user_timeout_pending: bool,     // まだ広告していないか
+    // 相手がオプションで広告したUser Timeoutと，それを採用するか(RFC5482のChangeable)
+    pub remote_user_timeout: Option<Duration>,
+    pub accept_remote_user_timeout: bool,
+    // ECN(RFC3168)
+    pub ecn: bool, // ECNを使うか．相手が非対応なら接続確立時にfalseにする
+    pub tos: u8,   // 送信用ソケットに最後に設定したIP_TOS．変わらなければ設定し直さない
+    // 相手がSYNで広告したMSS．送信するセグメントはこれとMSS定数の小さい方で分割する
+    pub remote_mss: u16,
+    // SYN cookie(RFC4987)．リスニングソケットのみ使用
+    pub syn_cookies: bool, // 半開きの接続が溢れたらSYN cookieを使うか
+    pub syn_cookie_sent: Option<SystemTime>, // 最後にSYN cookieを送った時刻
+    // TCP Fast Open(RFC7413)
+    pub fast_open: bool, // SYNに載ったデータを受け付けるか．リスニングソケットのみ使用
+    pub fast_open_cookie: Option<Vec<u8>>, // SYNかSYN/ACKに付けるcookie．空ならcookieを要求する
+    pub early_accepted: bool, // ハンドシェイクの完了前にacceptキューに入れたか
+    // TCP MD5署名(RFC2385)
+    pub md5_keys: HashMap<Ipv4Addr, Vec<u8>>, // 相手のアドレスごとの鍵．リスニングソケットのみ使用
+    pub md5_key: Option<Vec<u8>>, // 送受信するセグメントを署名する鍵．接続済みソケットのみ使用
+}
+
+/// キープアライブの設定(LinuxのTCP_KEEPIDLE, TCP_KEEPINTVL, TCP_KEEPCNTに相当)
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    pub idle: Duration,     // 最初のプローブを送るまでの無通信時間
+    pub interval: Duration, // プローブの間隔
+    pub count: u8,          // 接続を切るまでに送るプローブの数
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(7200),
+            interval: Duration::from_secs(75),
+            count: 9,
+        }
+    }
+}
+
+/// 接続が異常終了した理由．以降のソケット操作はこのエラーを返す
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketError {
+    TimedOut,
+    ConnectionReset,
+    ConnectionRefused,
 }
 
+impl Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketError::TimedOut => write!(f, "connection timed out"),
+            SocketError::ConnectionReset => write!(f, "connection reset by peer"),
+            SocketError::ConnectionRefused => write!(f, "connection refused"),
+        }
+    }
+}
+
+impl Error for SocketError {}
+
 #[derive(Clone, Debug)]
 pub struct RetransmissionQueueEntry {
-    pub packet: TCPPacket,
+    pub seq: u32,
+    pub len: u32, // ペイロードのサイズ．ペイロードは送信バッファから作り直す
+    pub flag: u8,
+    pub first_transmission_time: SystemTime,
     pub latest_transmission_time: SystemTime,
     pub transmission_count: u8,
+    pub delivered: u64,              // 送信時点のsend_param.delivered
+    pub delivered_time: SystemTime,  // 送信時点のsend_param.delivered_time
+    pub first_sent_time: SystemTime, // 送信時点のsend_param.first_sent_time
 }
 
 impl RetransmissionQueueEntry {
-    fn new(packet: TCPPacket) -> Self {
+    fn new(seq: u32, len: u32, flag: u8, send_param: &SendParam) -> Self {
         Self {
-            packet,
+            seq,
+            len,
+            flag,
+            first_transmission_time: SystemTime::now(),
             latest_transmission_time: SystemTime::now(),
             transmission_count: 1,
+            delivered: send_param.delivered,
+            delivered_time: send_param.delivered_time,
+            first_sent_time: send_param.first_sent_time,
         }
     }
+
+    /// セグメントが消費するシーケンス番号の終わり(SYNとFINは1つ消費する)
+    pub fn end(&self) -> u32 {
+        let mut end = self.seq + self.len;
+        if self.flag & (tcpflags::SYN | tcpflags::FIN) > 0 {
+            end += 1;
+        }
+        end
+    }
+}
+
+/// ackされたセグメントから得られるRTTと配送レートの計測値
+#[derive(Clone, Debug, Default)]
+pub struct RateSample {
+    pub rtt: Option<Duration>,
+    pub delivery_rate: Option<u64>, // バイト/秒
+    pub prior_delivered: u64,       // 計測に使ったセグメントの送信時点のdelivered
 }
 
 #[derive(Clone, Debug)]
 pub struct SendParam {
-    pub unacked_seq: u32, // 送信後まだackされていないseqの先頭
-    pub next: u32,        // 次の送信
-    pub window: u16,      // 送信ウィンドウサイズ
-    pub initial_seq: u32, // 初期送信seq
+    pub unacked_seq: u32,                     // 送信後まだackされていないseqの先頭
+    pub next: u32,                            // 次の送信
+    pub window: u32,                          // 送信ウィンドウサイズ(相手の受信ウィンドウ)
+    pub max_window: u32,                      // これまでに相手が広告した最大のウィンドウ
+    pub window_shift: u8,                     // 相手が広告するウィンドウのシフト数(Snd.Wind.Shift)
+    pub window_update_seq: u32,               // ウィンドウを更新したセグメントのseq(SND.WL1)
+    pub window_update_ack: u32,               // ウィンドウを更新したセグメントのack(SND.WL2)
+    pub initial_seq: u32,                     // 初期送信seq
+    pub dup_ack_count: u8,                    // 連続して受信した重複ACKの数
+    pub recover: u32,                         // 高速回復の終了条件となるseq（RFC6582のrecover）
+    pub in_fast_recovery: bool,               // 高速回復中かどうか
+    pub inflation: u32,                       // 高速回復中に重複ACKで膨らませた輻輳ウィンドウ
+    pub persist_deadline: Option<SystemTime>, // 次のウィンドウプローブを送る時刻
+    pub persist_backoff: u8,                  // ウィンドウプローブを送った回数
+    pub urgent_pointer: Option<u32>,          // 緊急データの次のseq(SND.UP)
+    pub push_seq: u32,                        // 最後に書き込まれたデータの次のseq
+    pub sws_deadline: Option<SystemTime>,     // SWS回避で保留した送信を諦めて送る時刻
+    pub next_send_time: SystemTime,           // ペーシングで次のセグメントを送れる時刻
+    pub cwr_pending: bool,                    // 次に送るデータセグメントにCWRを立てるか
+    pub cwr_seq: u32,                         // ECEに応答してウィンドウを縮めた時のnext
+    // 配送レートの計測に使う値(draft-cheng-iccrg-delivery-rate-estimation)
+    pub delivered: u64,              // これまでにackされたバイト数
+    pub delivered_time: SystemTime,  // deliveredを最後に更新した時刻
+    pub first_sent_time: SystemTime, // 計測区間の始まりとなるセグメントの送信時刻
+}
+
+impl SendParam {
+    /// 送信済みでまだackされていないバイト数
+    pub fn flight_size(&self) -> u32 {
+        self.next - self.unacked_seq
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct RecvParam {
-    pub next: u32,        // 次受信するseq
-    pub window: u16,      // 受信ウィンドウ
-    pub initial_seq: u32, // 初期受信seq
-    pub tail: u32,        // 受信seqの最後尾
+    pub next: u32,                        // 次受信するseq
+    pub window: u32,                      // 受信ウィンドウ(受信バッファの空き)
+    pub window_shift: u8,                 // 広告する受信ウィンドウのシフト数(Rcv.Wind.Shift)
+    pub initial_seq: u32,                 // 初期受信seq
+    pub tail: u32,                        // 受信seqの最後尾
+    pub advertised_edge: u32,             // 最後に広告した受信ウィンドウの右端のseq
+    pub unacked_segments: u8,             // まだACKを返していない受信セグメントの数
+    pub ack_deadline: Option<SystemTime>, // 遅延ACKを送信する期限
+    pub quick_acks: u8,                   // ACKを遅延させずに返す残りのセグメント数
+    pub urgent_seq: Option<u32>,          // 帯域外で受け取る緊急データのseq
+    pub ece_pending: bool,                // CWRを受け取るまでACKにECEを立てるか
+    // 受信バッファの自動チューニングに使う値(Linuxのrcv_rtt_estとrcvq_space)
+    pub rtt_seq: u32,                   // RTT計測の終わりとなるseq
+    pub rtt_time: Option<SystemTime>,   // RTT計測を始めた時刻
+    pub rtt: Option<Duration>,          // 受信側で計測したRTT
+    pub space: usize,                   // 1RTTの間にアプリケーションが読み出した最大のバイト数
+    pub space_seq: u32,                 // 読み出し量の計測を始めた時点の読み出し済みseq
+    pub space_time: Option<SystemTime>, // 読み出し量の計測を始めた時刻
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -71,9 +245,11 @@ pub enum TcpStatus {
     Established,
     FinWait1,
     FinWait2,
+    Closing,
     TimeWait,
     CloseWait,
     LastAck,
+    Closed,
 }
 
 impl Display for TcpStatus {
@@ -85,9 +261,11 @@ impl Display for TcpStatus {
             TcpStatus::Established => write!(f, "ESTABLISHED"),
             TcpStatus::FinWait1 => write!(f, "FINWAIT1"),
             TcpStatus::FinWait2 => write!(f, "FINWAIT2"),
+            TcpStatus::Closing => write!(f, "CLOSING"),
             TcpStatus::TimeWait => write!(f, "TIMEWAIT"),
             TcpStatus::CloseWait => write!(f, "CLOSEWAIT"),
             TcpStatus::LastAck => write!(f, "LASTACK"),
+            TcpStatus::Closed => write!(f, "CLOSED"),
         }
     }
 }
@@ -113,23 +291,90 @@ impl Socket {
                 unacked_seq: 0,
                 initial_seq: 0,
                 next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
+                window: SOCKET_BUFFER_SIZE as u32,
+                max_window: SOCKET_BUFFER_SIZE as u32,
+                window_shift: 0,
+                window_update_seq: 0,
+                window_update_ack: 0,
+                dup_ack_count: 0,
+                recover: 0,
+                in_fast_recovery: false,
+                inflation: 0,
+                persist_deadline: None,
+                persist_backoff: 0,
+                urgent_pointer: None,
+                push_seq: 0,
+                sws_deadline: None,
+                next_send_time: SystemTime::now(),
+                cwr_pending: false,
+                cwr_seq: 0,
+                delivered: 0,
+                delivered_time: SystemTime::now(),
+                first_sent_time: SystemTime::now(),
             },
             recv_param: RecvParam {
                 initial_seq: 0,
                 next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
+                window: SOCKET_BUFFER_SIZE as u32,
+                window_shift: 0,
                 tail: 0,
+                advertised_edge: 0,
+                unacked_segments: 0,
+                ack_deadline: None,
+                // 相手がスロースタート中の接続開始直後はACKを遅延させない
+                quick_acks: QUICK_ACK_SEGMENTS,
+                urgent_seq: None,
+                ece_pending: false,
+                rtt_seq: 0,
+                rtt_time: None,
+                rtt: None,
+                space: SOCKET_BUFFER_SIZE,
+                space_seq: 0,
+                space_time: None,
             },
             status,
-            recv_buffer: vec![0; SOCKET_BUFFER_SIZE],
+            recv_buffer: RingBuffer::new(SOCKET_BUFFER_SIZE),
             retransmission_queue: VecDeque::new(),
             connected_connection_queue: VecDeque::new(),
+            backlog: 0,
+            half_open: 0,
+            counted_half_open: false,
             listening_socket: None,
+            #[cfg(test)]
+            sent: Vec::new(),
             sender,
+            congestion: congestion::from_name(congestion::DEFAULT_CONGESTION_CONTROL)?,
+            nodelay: false,
+            send_buffer: RingBuffer::new(SEND_BUFFER_SIZE),
+            keepalive: None,
+            keepalive_probes: 0,
+            last_received: SystemTime::now(),
+            error: None,
+            read_shutdown: false,
+            linger: None,
+            oob_inline: false,
+            urgent_data: None,
+            released: false,
+            close_deadline: None,
+            user_timeout: None,
+            advertise_user_timeout: false,
+            user_timeout_pending: false,
+            remote_user_timeout: None,
+            accept_remote_user_timeout: false,
+            ecn: false,
+            tos: 0,
+            remote_mss: tcpoptions::DEFAULT_MSS,
+            syn_cookies: false,
+            syn_cookie_sent: None,
+            fast_open: false,
+            fast_open_cookie: None,
+            early_accepted: false,
+            md5_keys: HashMap::new(),
+            md5_key: None,
         })
     }
 
+    /// セグメントを送信する．データかSYN/FINを含むセグメントは再送キューに積む
     pub fn send_tcp_packet(
         &mut self,
         seq: u32,
@@ -137,15 +382,60 @@ impl Socket {
         flag: u8,
         payload: &[u8],
     ) -> Result<usize> {
-        let mut tcp_packet = TCPPacket::new(payload.len());
+        // 再送ではないデータセグメントだけをECN対応のパケットとして送る(RFC3168 6.1.5)．
+        // Fast Openでデータを載せたSYNは除く
+        let ect = self.ecn && !payload.is_empty() && flag & tcpflags::SYN == 0;
+        let sent_size = self.transmit_packet(seq, ack, flag, payload, ect)?;
+        if payload.is_empty() && flag == tcpflags::ACK {
+            return Ok(sent_size);
+        }
+        if self.retransmission_queue.is_empty() {
+            // 送信中のセグメントがなければ配送レートの計測区間をここから始める
+            self.send_param.delivered_time = SystemTime::now();
+            self.send_param.first_sent_time = SystemTime::now();
+        }
+        self.retransmission_queue
+            .push_back(RetransmissionQueueEntry::new(
+                seq,
+                payload.len() as u32,
+                flag,
+                &self.send_param,
+            ));
+        Ok(sent_size)
+    }
+
+    /// パケットを組み立てて送信する．ectならIPヘッダのECNフィールドをECT(0)にする
+    fn transmit_packet(
+        &mut self,
+        seq: u32,
+        ack: u32,
+        flag: u8,
+        payload: &[u8],
+        ect: bool,
+    ) -> Result<usize> {
+        let options = self.tcp_options(flag, payload);
+        let mut tcp_packet = TCPPacket::new(&options, payload.len());
         tcp_packet.set_src(self.local_port);
         tcp_packet.set_dest(self.remote_port);
         tcp_packet.set_seq(seq);
         tcp_packet.set_ack(ack);
-        tcp_packet.set_data_offset(5);
+        let mut flag = flag;
+        if self.ecn && self.recv_param.ece_pending && flag & tcpflags::SYN == 0 {
+            // 相手がCWRで応答するまでは全てのセグメントで輻輳を知らせる
+            flag |= tcpflags::ECE;
+        }
+        if let Some(pointer) = self.urgent_pointer_for(seq, flag) {
+            // 緊急データを送り終えるまでは全てのセグメントで緊急ポインタを知らせる
+            flag |= tcpflags::URG;
+            tcp_packet.set_urgent_pointer(pointer);
+        }
         tcp_packet.set_flag(flag);
-        tcp_packet.set_window_size(self.recv_param.window);
+        let window = self.advertised_window(flag);
+        tcp_packet.set_window_size(window);
         tcp_packet.set_payload(payload);
+        if let Some(key) = &self.md5_key {
+            tcp_packet.sign_md5(self.local_addr, self.remote_addr, key);
+        }
         tcp_packet.set_checksum(util::ipv4_checksum(
             &tcp_packet.packet(),
             8,
@@ -154,20 +444,471 @@ impl Socket {
             &self.remote_addr,
             IpNextHeaderProtocols::Tcp,
         ));
+        self.set_ect(ect)?;
         let sent_size = self
             .sender
             .send_to(tcp_packet.clone(), IpAddr::V4(self.remote_addr))
             .context(format!("failed to send: \n{:?}", tcp_packet))?;
 
         dbg!("sent", &tcp_packet);
-        if payload.is_empty() && tcp_packet.get_flag() == tcpflags::ACK {
-            return Ok(sent_size);
+        #[cfg(test)]
+        self.sent.push(tcp_packet.clone());
+        if flag & tcpflags::ACK > 0 {
+            // ACKを送ったので遅延させているACKは不要
+            self.recv_param.unacked_segments = 0;
+            self.recv_param.ack_deadline = None;
         }
-        self.retransmission_queue
-            .push_back(RetransmissionQueueEntry::new(tcp_packet));
         Ok(sent_size)
     }
 
+    /// 送信用ソケットのIP_TOSを設定して，以降に送るパケットのECNフィールドをECT(0)か0にする．
+    /// Layer4のチャネルではIPヘッダはカーネルが組み立てるので，TOSで指定する
+    fn set_ect(&mut self, ect: bool) -> Result<()> {
+        let tos = if ect { ECN_ECT0 } else { 0 };
+        if self.tos == tos {
+            // データ，ACK，再送が交互に続いても，値が変わらない限りシステムコールを呼ばない
+            return Ok(());
+        }
+        let value = tos as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                self.sender.socket.fd,
+                libc::IPPROTO_IP,
+                libc::IP_TOS,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error()).context("failed to set IP_TOS");
+        }
+        self.tos = tos;
+        Ok(())
+    }
+
+    /// seqから始まるセグメントに載せる緊急ポインタ(SND.UP - seq)．緊急データがなければNone
+    fn urgent_pointer_for(&self, seq: u32, flag: u8) -> Option<u16> {
+        let pointer = self.send_param.urgent_pointer?.checked_sub(seq)?;
+        if pointer == 0 || pointer > u16::MAX as u32 || flag & (tcpflags::SYN | tcpflags::RST) > 0 {
+            return None;
+        }
+        Some(pointer as u16)
+    }
+
+    /// 送信するセグメントに付けるオプションを生成する．
+    /// User Timeoutを付けられたら通知済みにし，cookieを付けられなければFast Openを諦める
+    fn tcp_options(&mut self, flag: u8, payload: &[u8]) -> Vec<u8> {
+        let (options, user_timeout_added, cookie_dropped) = self.build_tcp_options(flag, payload);
+        if user_timeout_added {
+            self.user_timeout_pending = false;
+        }
+        if cookie_dropped {
+            // cookieを付けられなければ，Fast Openを使わない通常のハンドシェイクにする
+            dbg!("no room for fast open option");
+            self.fast_open_cookie = None;
+        }
+        options
+    }
+
+    /// ソケットの状態を変えずにオプションを組み立てる．User Timeoutを付けたかと，
+    /// 収まらずにFast Openのcookieを付けられなかったかも返す．
+    /// MD5署名は省略できないので最初に場所を確保し，User TimeoutとFast Openは収まる時だけ付ける
+    fn build_tcp_options(&self, flag: u8, payload: &[u8]) -> (Vec<u8>, bool, bool) {
+        let mut options = Vec::new();
+        if self.md5_key.is_some() {
+            // 署名は送信する全てのセグメントに付ける
+            options.extend_from_slice(&tcpoptions::md5_signature());
+        }
+        if flag & tcpflags::SYN > 0 {
+            options.extend_from_slice(&tcpoptions::mss(MSS as u16));
+        }
+        let mut user_timeout_added = false;
+        if let Some(timeout) = self.user_timeout.filter(|_| self.advertise_user_timeout) {
+            // SYNと，設定が変わった後のデータのないセグメントで知らせる．
+            // データのあるセグメントに付けるとMSSを超えてしまうため
+            // 収まらなければ次のセグメントで知らせる
+            if flag & tcpflags::SYN > 0 || (self.user_timeout_pending && payload.is_empty()) {
+                user_timeout_added = push_option(&mut options, &tcpoptions::user_timeout(timeout));
+            }
+        }
+        // SYNでは常に提案し，SYN/ACKでは相手のSYNが提案していた時だけ応じる
+        if flag & tcpflags::SYN > 0
+            && (flag & tcpflags::ACK == 0 || self.recv_param.window_shift > 0)
+        {
+            options.extend_from_slice(&tcpoptions::window_scale(RECV_WINDOW_SHIFT));
+        }
+        let mut cookie_dropped = false;
+        if let Some(cookie) = &self.fast_open_cookie {
+            if flag & tcpflags::SYN > 0 {
+                cookie_dropped = !push_option(&mut options, &tcpoptions::fast_open(cookie));
+            }
+        }
+        (options, user_timeout_added, cookie_dropped)
+    }
+
+    /// Fast OpenのSYNに載せられるデータの最大サイズ．IPパケットがMTUに収まるように，
+    /// SYNに付けるオプションの分だけMSSから減らす．cookieを付けられなければデータは載せない
+    pub fn syn_data_size(&self) -> usize {
+        let (options, _, cookie_dropped) = self.build_tcp_options(tcpflags::SYN, &[]);
+        if self.fast_open_cookie.is_none() || cookie_dropped {
+            return 0;
+        }
+        MSS.saturating_sub((options.len() + 3) & !3)
+    }
+
+    /// 受信したセグメントのオプションを処理する
+    pub fn process_options(&mut self, packet: &TCPPacket) {
+        if let Some(mss) = packet
+            .get_option(tcpoptions::MSS)
+            .and_then(tcpoptions::parse_mss)
+            .filter(|_| packet.get_flag() & tcpflags::SYN > 0)
+        {
+            self.remote_mss = mss;
+        }
+        if let Some(data) = packet.get_option(tcpoptions::USER_TIMEOUT) {
+            self.remote_user_timeout = tcpoptions::parse_user_timeout(data);
+            dbg!("remote user timeout", self.remote_user_timeout);
+        }
+        if packet.get_flag() & tcpflags::SYN > 0
+            && matches!(self.status, TcpStatus::SynSent | TcpStatus::SynRcvd)
+        {
+            // 双方のSYNにウィンドウスケールオプションがあった時だけ，以降のウィンドウをシフトする(RFC7323 2.2)
+            match packet
+                .get_option(tcpoptions::WINDOW_SCALE)
+                .and_then(tcpoptions::parse_window_scale)
+            {
+                Some(shift) => {
+                    self.send_param.window_shift = shift;
+                    self.recv_param.window_shift = RECV_WINDOW_SHIFT;
+                }
+                None => {
+                    self.send_param.window_shift = 0;
+                    self.recv_param.window_shift = 0;
+                }
+            }
+            dbg!("window shift", self.send_param.window_shift);
+        }
+    }
+
+    /// 送信するデータセグメントの最大サイズ．相手がSYNで広告したMSSを超えないようにし，
+    /// 全てのセグメントに付けるMD5署名オプションがあればその分だけ減らす(RFC6691)
+    pub fn mss(&self) -> usize {
+        let mss = (self.remote_mss as usize).clamp(MIN_MSS, MSS);
+        if self.md5_key.is_some() {
+            // オプションは4バイト境界まで埋めて送る
+            mss - ((tcpoptions::md5_signature().len() + 3) & !3)
+        } else {
+            mss
+        }
+    }
+
+    /// 受信したセグメントが広告している相手の受信ウィンドウ．SYNのウィンドウはシフトしない
+    pub fn remote_window(&self, packet: &TCPPacket) -> u32 {
+        let window = packet.get_window_size() as u32;
+        if packet.get_flag() & tcpflags::SYN > 0 {
+            window
+        } else {
+            window << self.send_param.window_shift
+        }
+    }
+
+    /// 受信したセグメントのECNの情報を処理する(RFC3168 6.1.3)．ceはIPヘッダにCEマークが付いていたか．
+    /// CEマークを受信したら，CWRの立ったセグメントを受信するまで送信するACKにECEを立てる．
+    /// DCTCPのようにECNが必須の輻輳制御では，CEマークの有無をそのままECEで返す(RFC8257 3.2)
+    pub fn process_ecn(&mut self, flag: u8, ce: bool) -> Result<()> {
+        if !self.ecn || flag & tcpflags::SYN > 0 {
+            return Ok(());
+        }
+        if self.congestion.needs_ecn() {
+            if ce != self.recv_param.ece_pending {
+                if self.recv_param.unacked_segments > 0 {
+                    // 遅延させているACKは変化する前のCEマークの状態で返す
+                    self.send_ack()?;
+                }
+                dbg!("CE state changed", ce);
+                self.recv_param.ece_pending = ce;
+            }
+            return Ok(());
+        }
+        if flag & tcpflags::CWR > 0 {
+            self.recv_param.ece_pending = false;
+        }
+        if ce {
+            dbg!("CE received");
+            self.recv_param.ece_pending = true;
+        }
+        Ok(())
+    }
+
+    /// 実際に使うUser Timeout．自分で設定しておらず，アプリケーションが相手の値の採用を許可していれば，
+    /// 相手が広告した値をRFC5482に従って[MIN_REMOTE_USER_TIMEOUT, MAX_REMOTE_USER_TIMEOUT]に収めて使う
+    pub fn effective_user_timeout(&self) -> Option<Duration> {
+        self.user_timeout.or_else(|| {
+            let remote = self
+                .remote_user_timeout
+                .filter(|_| self.accept_remote_user_timeout);
+            remote.map(|timeout| {
+                cmp::min(
+                    cmp::max(timeout, MIN_REMOTE_USER_TIMEOUT),
+                    MAX_REMOTE_USER_TIMEOUT,
+                )
+            })
+        })
+    }
+
+    /// RFC1122の受信側SWS回避．広告するウィンドウの右端は，
+    /// min(バッファサイズの半分, MSS)以上広げられる時にだけ右に動かす
+    /// ヘッダにはシフト数だけ右シフトした値を載せる．SYNのウィンドウはシフトしない
+    fn advertised_window(&mut self, flag: u8) -> u16 {
+        let current = self
+            .recv_param
+            .advertised_edge
+            .saturating_sub(self.recv_param.next);
+        let window = self.recv_param.window;
+        if window >= current + self.window_update_threshold() || window < current {
+            self.recv_param.advertised_edge = self.recv_param.next + window;
+        }
+        let shift = if flag & tcpflags::SYN > 0 {
+            0
+        } else {
+            self.recv_param.window_shift
+        };
+        cmp::min(
+            self.recv_param
+                .advertised_edge
+                .saturating_sub(self.recv_param.next)
+                >> shift,
+            u16::MAX as u32,
+        ) as u16
+    }
+
+    /// 受信ウィンドウが十分に開き，相手にウィンドウ更新を送るべきかどうか
+    pub fn window_update_needed(&self) -> bool {
+        let current = self
+            .recv_param
+            .advertised_edge
+            .saturating_sub(self.recv_param.next);
+        self.recv_param.window >= current + self.window_update_threshold()
+    }
+
+    fn window_update_threshold(&self) -> u32 {
+        cmp::min(self.recv_buffer.capacity() / 2, MSS) as u32
+    }
+
+    /// 受信側でRTTを計測する(Linuxのtcp_rcv_rtt_measure)．受信ウィンドウ分のデータを
+    /// 受信するのにかかった時間をRTTとみなす．順番通りのデータを受信した時に呼ぶ
+    pub fn measure_recv_rtt(&mut self) {
+        let param = &mut self.recv_param;
+        let now = SystemTime::now();
+        if let Some(time) = param.rtt_time {
+            if param.next < param.rtt_seq {
+                return;
+            }
+            let sample = now.duration_since(time).unwrap_or_default();
+            param.rtt = match param.rtt {
+                // 小さい値はすぐに採用し，大きい値は平滑化する
+                Some(rtt) if sample > rtt => Some(rtt * 7 / 8 + sample / 8),
+                _ => Some(sample),
+            };
+        }
+        param.rtt_seq = param.next + param.window;
+        param.rtt_time = Some(now);
+    }
+
+    /// 受信バッファの自動チューニング(Linuxのtcp_rcv_space_adjust)．
+    /// RTT毎にアプリケーションが読み出したバイト数を計測し，これまでより多く読み出されていれば
+    /// その2倍を受信バッファの新しいサイズとして返す．広げる必要がなければNone
+    pub fn recv_space_adjust(&mut self) -> Option<usize> {
+        let rtt = self.recv_param.rtt?;
+        let now = SystemTime::now();
+        let copied_seq = self.recv_param.next - self.recv_buffer.len() as u32;
+        let param = &mut self.recv_param;
+        let time = match param.space_time {
+            Some(time) => time,
+            None => {
+                param.space_seq = copied_seq;
+                param.space_time = Some(now);
+                return None;
+            }
+        };
+        if now.duration_since(time).unwrap_or_default() < rtt {
+            return None;
+        }
+        let copied = (copied_seq - param.space_seq) as usize;
+        param.space_seq = copied_seq;
+        param.space_time = Some(now);
+        if copied <= param.space {
+            return None;
+        }
+        param.space = copied;
+        // ウィンドウスケールを使わない接続では，u16で広告できる大きさまでしか広げない
+        let max = cmp::min(
+            MAX_RECV_BUFFER_SIZE,
+            (u16::MAX as usize) << self.recv_param.window_shift,
+        );
+        let size = cmp::min(2 * copied, max);
+        if size > self.recv_buffer.capacity() {
+            Some(size)
+        } else {
+            None
+        }
+    }
+
+    /// 受信バッファをsizeまで広げ，広げた分だけ受信ウィンドウを開く
+    pub fn grow_recv_buffer(&mut self, size: usize) {
+        let grown = size.saturating_sub(self.recv_buffer.capacity());
+        self.recv_buffer.grow(size);
+        self.recv_param.window += grown as u32;
+    }
+
+    /// リスニングソケットに設定されたオプションを引き継ぐ．SYN/ACKを送る前に呼ぶ
+    pub fn inherit(&mut self, listening_socket: &Socket) -> Result<()> {
+        self.listening_socket = Some(listening_socket.get_sock_id());
+        self.congestion = congestion::from_name(listening_socket.congestion.name())?;
+        self.nodelay = listening_socket.nodelay;
+        self.keepalive = listening_socket.keepalive;
+        self.linger = listening_socket.linger;
+        self.user_timeout = listening_socket.user_timeout;
+        self.advertise_user_timeout = listening_socket.advertise_user_timeout;
+        self.accept_remote_user_timeout = listening_socket.accept_remote_user_timeout;
+        self.md5_key = listening_socket.md5_keys.get(&self.remote_addr).cloned();
+        Ok(())
+    }
+
+    /// 現在のrecv_param.nextをackする
+    pub fn send_ack(&mut self) -> Result<()> {
+        self.send_tcp_packet(
+            self.send_param.next,
+            self.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        )?;
+        Ok(())
+    }
+
+    /// RSTを送信する．RSTは再送しない
+    pub fn send_reset(&mut self) -> Result<()> {
+        self.transmit_packet(
+            self.send_param.next,
+            self.recv_param.next,
+            tcpflags::RST | tcpflags::ACK,
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// 受信済みのseq(next - 1)を使ったデータのないセグメントを送り，相手にACKを返させる．
+    /// ウィンドウプローブに使う
+    pub fn send_probe(&mut self) -> Result<()> {
+        self.send_tcp_packet(
+            self.send_param.next - 1,
+            self.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        )?;
+        Ok(())
+    }
+
+    /// 送信バッファにあってまだ送信していないバイト数
+    pub fn unsent_size(&self) -> usize {
+        self.send_buffer
+            .len()
+            .saturating_sub(self.send_param.flight_size() as usize)
+    }
+
+    /// 再送キューのindex番目のセグメントを送信バッファのデータから作り直して再送する．
+    /// 続くセグメントと合わせてもMSSに収まる場合は1つのセグメントにまとめる
+    pub fn retransmit(&mut self, index: usize) -> Result<()> {
+        let mut item = match self.retransmission_queue.remove(index) {
+            Some(item) => item,
+            None => return Ok(()),
+        };
+        if item.flag & tcpflags::SYN > 0 && item.len > 0 {
+            // Fast OpenのSYNが届かないのは，経路上でデータ付きのSYNが捨てられているのかもしれない．
+            // データなしで再送し，データは接続後に送り直す(RFC7413 4.2.1)
+            dbg!("fast open syn timeout");
+            item.len = 0;
+        }
+        while item.flag & (tcpflags::SYN | tcpflags::FIN) == 0 {
+            match self.retransmission_queue.get(index) {
+                Some(next)
+                    if next.seq == item.end()
+                        && next.flag & tcpflags::SYN == 0
+                        && (item.len + next.len) as usize <= self.mss() =>
+                {
+                    item.len += next.len;
+                    item.flag |= next.flag;
+                    item.transmission_count =
+                        cmp::max(item.transmission_count, next.transmission_count);
+                    item.first_transmission_time =
+                        cmp::min(item.first_transmission_time, next.first_transmission_time);
+                    self.retransmission_queue.remove(index);
+                }
+                _ => break,
+            }
+        }
+        let mut payload = vec![0; item.len as usize];
+        let offset = item.seq.wrapping_sub(self.send_param.unacked_seq) as usize;
+        self.send_buffer.peek(offset, &mut payload);
+        self.transmit_packet(item.seq, self.recv_param.next, item.flag, &payload, false)
+            .context("failed to retransmit")?;
+        // User Timeoutが長いと上限なく再送するので，溢れないようにする
+        item.transmission_count = item.transmission_count.saturating_add(1);
+        item.latest_transmission_time = SystemTime::now();
+        self.retransmission_queue.insert(index, item);
+        Ok(())
+    }
+
+    /// 再送キューから未ackの先頭セグメント(seqがunacked_seqのもの)を探して再送する
+    pub fn retransmit_unacked_segment(&mut self) -> Result<()> {
+        let unacked_seq = self.send_param.unacked_seq;
+        if let Some(index) = self
+            .retransmission_queue
+            .iter()
+            .position(|item| item.seq == unacked_seq)
+        {
+            self.retransmit(index)?;
+        }
+        Ok(())
+    }
+
+    /// 未ackの先頭セグメントをもう一度送る．相手のSYNの再送に応じてSYN/ACKを送り直す時に使い，
+    /// タイムアウトによる再送ではないので再送回数と再送タイマーは変えない
+    pub fn resend_unacked_segment(&mut self) -> Result<()> {
+        let unacked_seq = self.send_param.unacked_seq;
+        let (seq, len, flag) = match self
+            .retransmission_queue
+            .iter()
+            .find(|item| item.seq == unacked_seq)
+        {
+            Some(item) => (item.seq, item.len, item.flag),
+            None => return Ok(()),
+        };
+        let mut payload = vec![0; len as usize];
+        self.send_buffer.peek(0, &mut payload);
+        self.transmit_packet(seq, self.recv_param.next, flag, &payload, false)
+            .context("failed to resend")?;
+        Ok(())
+    }
+
+    /// remote_addrから届いたセグメントの署名を検証する鍵．リスニングソケットでは相手ごとの鍵を使う
+    pub fn md5_key_for(&self, remote_addr: Ipv4Addr) -> Option<&[u8]> {
+        if self.status == TcpStatus::Listen {
+            self.md5_keys.get(&remote_addr).map(|key| key.as_slice())
+        } else {
+            self.md5_key.as_deref()
+        }
+    }
+
+    /// 接続が異常終了していればその理由をエラーとして返す
+    pub fn check_error(&self) -> Result<()> {
+        match self.error {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+
     pub fn get_sock_id(&self) -> SockID {
         SockID(
             self.local_addr,
@@ -177,3 +918,56 @@ impl Socket {
         )
     }
 }
+
+/// オプション領域に収まる時だけoptionを追加し，追加したかを返す
+fn push_option(options: &mut Vec<u8>, option: &[u8]) -> bool {
+    if options.len() + option.len() > tcpoptions::MAX_OPTIONS_LEN {
+        return false;
+    }
+    options.extend_from_slice(option);
+    true
+}
+
+/// ソケットを生成せずに，sock_idの接続へデータのないセグメントを送信する．
+/// SYN cookieを使う時のSYN/ACKのように，接続の状態を持たずに応答する時に使う．
+/// オプションはSYNならMSSと，md5_keyがあればMD5署名だけを付ける
+pub fn send_stateless_packet(
+    sender: &mut TransportSender,
+    sock_id: SockID,
+    seq: u32,
+    ack: u32,
+    flag: u8,
+    md5_key: Option<&[u8]>,
+) -> Result<usize> {
+    let SockID(local_addr, remote_addr, local_port, remote_port) = sock_id;
+    let mut options = match md5_key {
+        Some(_) => tcpoptions::md5_signature().to_vec(),
+        None => Vec::new(),
+    };
+    if flag & tcpflags::SYN > 0 {
+        options.extend_from_slice(&tcpoptions::mss(MSS as u16));
+    }
+    let mut tcp_packet = TCPPacket::new(&options, 0);
+    tcp_packet.set_src(local_port);
+    tcp_packet.set_dest(remote_port);
+    tcp_packet.set_seq(seq);
+    tcp_packet.set_ack(ack);
+    tcp_packet.set_flag(flag);
+    tcp_packet.set_window_size(SOCKET_BUFFER_SIZE as u16);
+    if let Some(key) = md5_key {
+        tcp_packet.sign_md5(local_addr, remote_addr, key);
+    }
+    tcp_packet.set_checksum(util::ipv4_checksum(
+        tcp_packet.packet(),
+        8,
+        &[],
+        &local_addr,
+        &remote_addr,
+        IpNextHeaderProtocols::Tcp,
+    ));
+    let sent_size = sender
+        .send_to(tcp_packet.clone(), IpAddr::V4(remote_addr))
+        .context(format!("failed to send: \n{:?}", tcp_packet))?;
+    dbg!("sent", &tcp_packet);
+    Ok(sent_size)
+}
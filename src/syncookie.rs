@@ -0,0 +1,150 @@
+use crate::socket::SockID;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// cookieの構成(上位から): 0 | カウンタ4ビット | MSSのインデックス2ビット | ハッシュ25ビット．
+// 自分で選ぶ初期seqと同じく2^31未満に収め，接続中にseqがu32を超えないようにする
+const COUNTER_SHIFT: u32 = 27;
+const COUNTER_MASK: u32 = 0b1111;
+const MSS_SHIFT: u32 = 25;
+const HASH_MASK: u32 = (1 << MSS_SHIFT) - 1;
+const COUNTER_PERIOD: u64 = 64; // カウンタを進める間隔(秒)
+const MAX_COUNTER_AGE: u32 = 1; // 受け付ける古いカウンタ(1つ前の周期まで)
+
+/// 生成したcookieが受け付けられる最長の時間
+pub const MAX_COOKIE_AGE: Duration =
+    Duration::from_secs(COUNTER_PERIOD * (MAX_COUNTER_AGE as u64 + 1));
+
+/// cookieに載せられるMSS．相手が広告したMSSを超えない最大のものを選ぶ
+const MSS_TABLE: [u16; 4] = [536, 1220, 1440, 1460];
+
+/// SYN cookie(RFC4987)を生成する．SYN/ACKの初期送信seqとして使う．
+/// sock_idは接続済みソケットのID，peer_seqはSYNのseq，mssは相手が広告したMSS．
+/// 相手のMSSがMSS_TABLEのどれよりも小さければ，相手のMSSを守れるcookieは作れないのでNoneを返す
+pub fn generate(secret: u64, sock_id: SockID, peer_seq: u32, mss: u16) -> Option<u32> {
+    let counter = current_counter();
+    let index = MSS_TABLE.iter().rposition(|&m| m <= mss)? as u32;
+    Some(
+        counter << COUNTER_SHIFT
+            | index << MSS_SHIFT
+            | hash(secret, sock_id, peer_seq, counter, index),
+    )
+}
+
+/// 最後のACKでackされたcookie(ack - 1)を検証し，正しければcookieに載せたMSSを返す．
+/// peer_seqはACKのseq - 1(SYNのseq)
+pub fn validate(secret: u64, sock_id: SockID, peer_seq: u32, cookie: u32) -> Option<u16> {
+    let counter = cookie >> COUNTER_SHIFT;
+    let age = current_counter().wrapping_sub(counter) & COUNTER_MASK;
+    if age > MAX_COUNTER_AGE {
+        dbg!("expired syn cookie", age);
+        return None;
+    }
+    let index = (cookie >> MSS_SHIFT) & 0b11;
+    if cookie & HASH_MASK != hash(secret, sock_id, peer_seq, counter, index) {
+        return None;
+    }
+    Some(MSS_TABLE[index as usize])
+}
+
+/// COUNTER_PERIOD秒毎に進む4ビットのカウンタ
+fn current_counter() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / COUNTER_PERIOD) as u32 & COUNTER_MASK
+}
+
+/// 秘密の値と接続の情報からcookieのハッシュ部分を計算する．
+/// MSSのインデックスも混ぜ，書き換えられたcookieで大きなMSSを使わせられないようにする
+fn hash(secret: u64, sock_id: SockID, peer_seq: u32, counter: u32, index: u32) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    secret.hash(&mut hasher);
+    sock_id.hash(&mut hasher);
+    peer_seq.hash(&mut hasher);
+    counter.hash(&mut hasher);
+    index.hash(&mut hasher);
+    hasher.finish() as u32 & HASH_MASK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const SECRET: u64 = 0x0123_4567_89ab_cdef;
+
+    fn sock_id() -> SockID {
+        SockID(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 1, 1),
+            40000,
+            50000,
+        )
+    }
+
+    /// age周期前のカウンタで作ったcookie
+    fn cookie_with_age(age: u32, peer_seq: u32) -> u32 {
+        let counter = current_counter().wrapping_sub(age) & COUNTER_MASK;
+        counter << COUNTER_SHIFT | 3 << MSS_SHIFT | hash(SECRET, sock_id(), peer_seq, counter, 3)
+    }
+
+    #[test]
+    fn test_generate_and_validate() {
+        let cookie = generate(SECRET, sock_id(), 1000, 1460).unwrap();
+        assert_eq!(validate(SECRET, sock_id(), 1000, cookie), Some(1460));
+        // 別の接続，別のSYN，別の秘密の値では検証に失敗する
+        let mut other = sock_id();
+        other.3 += 1;
+        assert_eq!(validate(SECRET, other, 1000, cookie), None);
+        assert_eq!(validate(SECRET, sock_id(), 1001, cookie), None);
+        assert_eq!(validate(SECRET + 1, sock_id(), 1000, cookie), None);
+        assert_eq!(validate(SECRET, sock_id(), 1000, cookie ^ 1), None);
+    }
+
+    #[test]
+    fn test_mss_round_trip() {
+        // 相手が広告したMSSを超えない最大のMSSを載せる
+        for &(mss, expected) in &[
+            (1460, 1460),
+            (1450, 1440),
+            (1300, 1220),
+            (1000, 536),
+            (536, 536),
+        ] {
+            let cookie = generate(SECRET, sock_id(), 1000, mss).unwrap();
+            assert_eq!(validate(SECRET, sock_id(), 1000, cookie), Some(expected));
+        }
+        // 表の最小値より小さいMSSはcookieに載せられない
+        assert_eq!(generate(SECRET, sock_id(), 1000, 535), None);
+        // MSSのインデックスを書き換えたcookieは検証に失敗する
+        let cookie = generate(SECRET, sock_id(), 1000, 536).unwrap();
+        assert_eq!(
+            validate(SECRET, sock_id(), 1000, cookie | 3 << MSS_SHIFT),
+            None
+        );
+    }
+
+    #[test]
+    fn test_expired_cookie() {
+        assert_eq!(
+            validate(SECRET, sock_id(), 1000, cookie_with_age(1, 1000)),
+            Some(1460)
+        );
+        assert_eq!(
+            validate(SECRET, sock_id(), 1000, cookie_with_age(2, 1000)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cookie_below_2_31() {
+        // どの周期のカウンタでも，cookieを初期seqにしてseqがu32を超えない
+        for age in 0..=COUNTER_MASK {
+            assert!(cookie_with_age(age, 1000) < 1 << 31);
+        }
+        assert!(generate(SECRET, sock_id(), 1000, 1460).unwrap() < 1 << 31);
+    }
+}
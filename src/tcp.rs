@@ -1,22 +1,40 @@
+use crate::congestion::{self, AckSample};
+use crate::fastopen;
 use crate::packet::TCPPacket;
-use crate::socket::{SockID, Socket, TcpStatus};
+pub use crate::socket::Keepalive;
+use crate::socket::{
+    self, RateSample, RetransmissionQueueEntry, SendParam, SockID, Socket, SocketError, TcpStatus,
+    ECN_CE, QUICK_ACK_SEGMENTS,
+};
+use crate::syncookie;
 use crate::tcpflags;
+use crate::tcpoptions;
 use anyhow::{Context, Result};
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
 use pnet::transport::{self, TransportChannelType};
 use rand::{rngs::ThreadRng, Rng};
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Shutdown};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::{cmp, ops::Range, str, thread};
 
 const UNDETERMINED_IP_ADDR: std::net::Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const UNDETERMINED_PORT: u16 = 0;
 const MAX_TRANSMITTION: u8 = 5;
 const RETRANSMITTION_TIMEOUT: u64 = 3;
-const MSS: usize = 1460;
+const DUPLICATE_ACK_THRESHOLD: u8 = 3;
+const MAX_PERSIST_TIMEOUT: u64 = 60;
+const SWS_OVERRIDE_TIMEOUT: u64 = 200; // ミリ秒
+const DELAYED_ACK_TIMEOUT: u64 = 200; // ミリ秒
+const MAX_SEGMENT_LIFETIME: u64 = 30; // 秒．TIME_WAITには2MSL留まる
+const FIN_WAIT2_TIMEOUT: u64 = 60; // 秒．closeされたFIN_WAIT2のソケットが相手のFINを待つ時間(tcp_fin_timeout)
+const TIMER_INTERVAL: u64 = 100; // ミリ秒
+const MAX_RECV_BUFFER_MEMORY: usize = 16 * 1024 * 1024; // 全ソケットの受信バッファの合計の上限
+const CHALLENGE_ACK_LIMIT: u32 = 1000; // 1秒間に送るチャレンジACKの数の平均的な上限
+const MAX_BACKLOG: usize = 4096; // listenのbacklogの上限(Linuxのsomaxconn)
 const PORT_RANGE: Range<u16> = 40000..60000;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -31,6 +49,7 @@ pub enum TCPEventKind {
     Acked,
     DataArrived,
     ConnectionClosed,
+    Aborted,
 }
 
 impl TCPEvent {
@@ -42,15 +61,22 @@ impl TCPEvent {
 pub struct TCP {
     sockets: RwLock<HashMap<SockID, Socket>>,
     event_condvar: (Mutex<Option<TCPEvent>>, Condvar),
+    ecn: AtomicBool,        // 新しい接続でECNを使うか．全てのソケットで共通
+    syn_cookie_secret: u64, // SYN cookieのハッシュに混ぜる秘密の値
+    fast_open_secret: u64,  // Fast Openのcookieのハッシュに混ぜる秘密の値
+    // サーバーから受け取ったFast Openのcookie．サーバーのアドレスごとに覚えておく
+    fast_open_cookies: Mutex<HashMap<Ipv4Addr, Vec<u8>>>,
+    // connectで使うMD5署名の鍵．接続先のアドレスごとに設定する
+    md5_keys: Mutex<HashMap<Ipv4Addr, Vec<u8>>>,
+    // connectで使う輻輳制御アルゴリズムの名前．接続先のアドレスごとに設定する
+    connect_congestion: Mutex<HashMap<Ipv4Addr, String>>,
+    // チャレンジACKを数え始めた時刻と，そこから1秒間に送れる残りの数．全ソケットで共有する
+    challenge_acks: Mutex<(Instant, u32)>,
 }
 
 impl TCP {
     pub fn new() -> Arc<Self> {
-        let sockets = RwLock::new(HashMap::new());
-        let tcp = Arc::new(Self {
-            sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
-        });
+        let tcp = Arc::new(Self::without_threads());
         let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
             // パケットの受信用スレッド
@@ -64,77 +90,141 @@ impl TCP {
         tcp
     }
 
+    /// 受信スレッドとタイマースレッドを起動せずに生成する
+    fn without_threads() -> Self {
+        Self {
+            sockets: RwLock::new(HashMap::new()),
+            event_condvar: (Mutex::new(None), Condvar::new()),
+            ecn: AtomicBool::new(false),
+            syn_cookie_secret: rand::thread_rng().gen(),
+            fast_open_secret: rand::thread_rng().gen(),
+            fast_open_cookies: Mutex::new(HashMap::new()),
+            md5_keys: Mutex::new(HashMap::new()),
+            connect_congestion: Mutex::new(HashMap::new()),
+            challenge_acks: Mutex::new((Instant::now(), challenge_ack_limit())),
+        }
+    }
+
     /// タイマースレッド用の関数
     /// 全てのソケットの再送キューを見て，タイムアウトしているパケットを再送する
+    /// 期限が来た遅延ACKとウィンドウプローブ，送信バッファに残っているデータの送信，キープアライブも行う
     fn timer(&self) {
         dbg!("begin timer thread");
         loop {
             let mut table = self.sockets.write().unwrap();
-            for (sock_id, socket) in table.iter_mut() {
-                while let Some(mut item) = socket.retransmission_queue.pop_front() {
-                    // 再送キューからackされたセグメントを除去する
-                    // established state以外の時に送信されたセグメントを除去するために必要
-                    if socket.send_param.unacked_seq > item.packet.get_seq() {
-                        // ackされてる
-                        dbg!("successfully acked", item.packet.get_seq());
-                        socket.send_param.window += item.packet.payload().len() as u16;
-                        self.publish_event(*sock_id, TCPEventKind::Acked);
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && socket.status == TcpStatus::LastAck
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
-                        continue;
-                    }
-                    // タイムアウトを確認
-                    if item.latest_transmission_time.elapsed().unwrap()
-                        < Duration::from_secs(RETRANSMITTION_TIMEOUT)
-                    {
-                        // 取り出したエントリがタイムアウトしてないなら，キューの以降のエントリもタイムアウトしてない
-                        // 先頭に戻す
-                        socket.retransmission_queue.push_front(item);
-                        break;
-                    }
-                    // ackされてなければ再送
-                    if item.transmission_count < MAX_TRANSMITTION {
-                        // 再送
-                        dbg!("retransmit");
+            let mut expired = Vec::new();
+            let mut released = Vec::new();
+            for socket in table.values_mut() {
+                if socket.counted_half_open && socket.status != TcpStatus::SynRcvd {
+                    // 異常終了などでSYNRCVDを抜けたソケットを半開きの接続の数から除く
+                    released.push(socket.get_sock_id());
+                }
+                // 遅延ACKの期限が来ていればACKを返す
+                if let Some(deadline) = socket.recv_param.ack_deadline {
+                    if deadline <= SystemTime::now() {
+                        dbg!("delayed ack timeout");
                         socket
-                            .sender
-                            .send_to(item.packet.clone(), IpAddr::V4(socket.remote_addr))
-                            .context("failed to retransmit")
+                            .send_ack()
+                            .context("failed to send delayed ack")
                             .unwrap();
-                        item.transmission_count += 1;
-                        item.latest_transmission_time = SystemTime::now();
-                        socket.retransmission_queue.push_back(item);
-                        break;
+                    }
+                }
+                // 相手の受信ウィンドウが0の間はウィンドウプローブを送る
+                probe_zero_window(socket)
+                    .context("failed to send window probe")
+                    .unwrap();
+                self.keepalive(socket)
+                    .context("failed to send keepalive probe")
+                    .unwrap();
+                // ペーシングやSWS回避で保留しているデータを送る
+                if !socket.send_buffer.is_empty() {
+                    self.transmit(socket, false)
+                        .context("failed to transmit")
+                        .unwrap();
+                }
+                // closeされたTIME_WAITとFIN_WAIT2のソケットは，期限が来たら破棄する
+                if matches!(socket.status, TcpStatus::TimeWait | TcpStatus::FinWait2)
+                    && socket.released
+                    && matches!(socket.close_deadline, Some(deadline) if deadline <= SystemTime::now())
+                {
+                    dbg!("time wait expired", socket.get_sock_id());
+                    expired.push(socket.get_sock_id());
+                    continue;
+                }
+                // 再送キューからackされたセグメントを除去する
+                // established state以外の時に送信されたセグメントを除去するために必要
+                self.delete_acked_segment_from_retransmission_queue(socket);
+                let item = match socket.retransmission_queue.front() {
+                    Some(item) => item,
+                    None => continue,
+                };
+                // タイムアウトを確認．先頭のエントリがタイムアウトしてないなら，以降のエントリもタイムアウトしてない
+                if item.latest_transmission_time.elapsed().unwrap()
+                    < Duration::from_secs(RETRANSMITTION_TIMEOUT)
+                {
+                    continue;
+                }
+                if socket.status == TcpStatus::SynRcvd
+                    && item.transmission_count >= MAX_TRANSMITTION
+                {
+                    dbg!("synrcvd expired", socket.get_sock_id());
+                    if socket.early_accepted {
+                        // Fast Openでacceptされた接続はアプリケーションにエラーを知らせる
+                        self.abort(socket, SocketError::TimedOut);
                     } else {
-                        dbg!("reached MAX_TRANSMITTION");
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && (socket.status == TcpStatus::LastAck
-                                || socket.status == TcpStatus::FinWait1
-                                || socket.status == TcpStatus::FinWait2)
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
+                        // SYN/ACKの再送が上限に達した半開きの接続は，acceptされていないので破棄する
+                        expired.push(socket.get_sock_id());
+                    }
+                    continue;
+                }
+                if let Some(timeout) = socket.effective_user_timeout() {
+                    // User Timeoutが設定されていれば再送回数ではなく経過時間だけで諦める
+                    if item.first_transmission_time.elapsed().unwrap_or_default() >= timeout {
+                        dbg!("user timeout");
+                        self.abort(socket, SocketError::TimedOut);
+                        continue;
                     }
+                } else if item.transmission_count >= MAX_TRANSMITTION {
+                    dbg!("reached MAX_TRANSMITTION");
+                    self.abort(socket, SocketError::TimedOut);
+                    continue;
                 }
+                // ackされてなければ再送
+                dbg!("retransmit", item.seq);
+                if item.len > 0 {
+                    // 前回のタイムアウト以前に送信したセグメントは同じ損失として扱い，ssthreshは下げ直さない
+                    let new_loss = item.seq >= socket.send_param.recover;
+                    on_retransmission_timeout(socket, new_loss);
+                }
+                socket
+                    .retransmit(0)
+                    .context("failed to retransmit")
+                    .unwrap();
+            }
+            for sock_id in released {
+                release_half_open(&mut table, sock_id);
+            }
+            for sock_id in expired {
+                release_half_open(&mut table, sock_id);
+                table.remove(&sock_id);
             }
             // ロックを外して待機する
             drop(table);
-            thread::sleep(Duration::from_millis(100));
+            thread::sleep(Duration::from_millis(TIMER_INTERVAL));
         }
     }
 
-    /// リスニングソケットを生成してソケットIDを返す
-    pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
-        let socket = Socket::new(
+    /// リスニングソケットを生成してソケットIDを返す．
+    /// backlogは半開きの接続(SYNRCVD)と，acceptされていない接続済みソケットのそれぞれの上限
+    pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16, backlog: usize) -> Result<SockID> {
+        let mut socket = Socket::new(
             local_addr,
             UNDETERMINED_IP_ADDR, // まだ接続先IPアドレスは未定
             local_port,
             UNDETERMINED_PORT, // まだ接続先ポート番号は未定
             TcpStatus::Listen,
         )?;
+        socket.backlog = backlog.clamp(1, MAX_BACKLOG);
         let mut lock = self.sockets.write().unwrap();
         let sock_id = socket.get_sock_id();
         lock.insert(sock_id, socket);
@@ -168,6 +258,18 @@ impl TCP {
 
     /// ターゲットに接続し，接続済みソケットのIDを返す
     pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockID> {
+        self.open(addr, port, None)
+    }
+
+    /// TCP Fast Open(RFC7413)で接続し，dataを送る(LinuxのTCP_FASTOPEN_CONNECTに相当)．
+    /// サーバーのcookieを持っていればdataをSYNに載せ，持っていなければcookieを要求する．
+    /// SYNに載せなかったデータや，サーバーがcookieを拒否して受け取らなかったデータは接続後に送る
+    pub fn connect_with_data(&self, addr: Ipv4Addr, port: u16, data: &[u8]) -> Result<SockID> {
+        self.open(addr, port, Some(data))
+    }
+
+    /// active openの処理．dataがあればFast Openを使う
+    fn open(&self, addr: Ipv4Addr, port: u16, data: Option<&[u8]>) -> Result<SockID> {
         let mut rng = rand::thread_rng();
         let mut socket = Socket::new(
             get_source_addr_to(addr)?,
@@ -177,18 +279,63 @@ impl TCP {
             TcpStatus::SynSent,
         )?;
         socket.send_param.initial_seq = rng.gen_range(1..1 << 31);
-        socket.send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[])?;
+        socket.md5_key = self.md5_keys.lock().unwrap().get(&addr).cloned();
+        if let Some(name) = self.connect_congestion.lock().unwrap().get(&addr) {
+            socket.congestion = congestion::from_name(name)?;
+        }
+        let flag = self.syn_flag(&mut socket);
+        let mut syn_payload = Vec::new();
+        let mut copied = 0;
+        if let Some(data) = data {
+            // 送信バッファの先頭はSYNの次のseqに対応させる
+            copied = socket.send_buffer.push(data);
+            socket.send_param.push_seq = socket.send_param.initial_seq + 1 + copied as u32;
+            let cookie = self.fast_open_cookies.lock().unwrap().get(&addr).cloned();
+            let has_cookie = cookie.is_some();
+            socket.fast_open_cookie = Some(cookie.unwrap_or_default());
+            if has_cookie {
+                syn_payload = data[..cmp::min(copied, socket.syn_data_size())].to_vec();
+                dbg!("fast open with cookie", syn_payload.len());
+            }
+        }
+        socket.send_tcp_packet(socket.send_param.initial_seq, 0, flag, &syn_payload)?;
         socket.send_param.unacked_seq = socket.send_param.initial_seq;
-        socket.send_param.next = socket.send_param.initial_seq + 1;
+        socket.send_param.next = socket.send_param.initial_seq + 1 + syn_payload.len() as u32;
         let mut table = self.sockets.write().unwrap();
         let sock_id = socket.get_sock_id();
         table.insert(sock_id, socket);
         // ロックを外してイベントの待機．受信スレッドがロックを取得できるようにするため．
         drop(table);
         self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if let Err(error) = socket.check_error() {
+            // SYNの再送が上限に達した
+            table.remove(&sock_id);
+            return Err(error);
+        }
+        drop(table);
+        if let Some(data) = data {
+            // 送信バッファに入りきらなかったデータ
+            if copied < data.len() {
+                self.send(sock_id, &data[copied..])?;
+            }
+        }
         Ok(sock_id)
     }
 
+    /// active openで送るSYNのフラグ．ECNを使うならECN-setup SYNにする(RFC3168 6.1.1)
+    fn syn_flag(&self, socket: &mut Socket) -> u8 {
+        if self.ecn.load(Ordering::Relaxed) || socket.congestion.needs_ecn() {
+            socket.ecn = true;
+            tcpflags::SYN | tcpflags::ECE | tcpflags::CWR
+        } else {
+            tcpflags::SYN
+        }
+    }
+
     /// データをバッファに読み込んで，読み込んだサイズを返す．FINを読み込んだ場合は0を返す
     /// パケットが届くまでブロックする
     pub fn recv(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
@@ -196,11 +343,19 @@ impl TCP {
         let mut socket = table
             .get_mut(&sock_id)
             .context(format!("no such socket: {:?}", sock_id))?;
-        let mut received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
+        let mut received_size = readable_size(socket);
         while received_size == 0 {
+            socket.check_error()?;
+            if socket.read_shutdown {
+                return Ok(0);
+            }
             // ペイロードを受信 or FINを受信でスキップ
             match socket.status {
-                TcpStatus::CloseWait | TcpStatus::LastAck | TcpStatus::TimeWait => break,
+                TcpStatus::CloseWait
+                | TcpStatus::LastAck
+                | TcpStatus::Closing
+                | TcpStatus::TimeWait
+                | TcpStatus::Closed => break,
                 _ => {}
             }
             // ロックを外してイベントの待機．受信スレッドがロックを取得できるようにするため．
@@ -211,67 +366,231 @@ impl TCP {
             socket = table
                 .get_mut(&sock_id)
                 .context(format!("no such socket: {:?}", sock_id))?;
-            received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
+            received_size = readable_size(socket);
         }
         let copy_size = cmp::min(buffer.len(), received_size);
-        buffer[..copy_size].copy_from_slice(&socket.recv_buffer[..copy_size]);
-        socket.recv_buffer.copy_within(copy_size.., 0);
-        socket.recv_param.window += copy_size as u16;
+        let copy_size = socket.recv_buffer.peek(0, &mut buffer[..copy_size]);
+        socket.recv_buffer.consume(copy_size);
+        socket.recv_param.window += copy_size as u32;
+        if let Some(size) = socket.recv_space_adjust() {
+            // 全ソケットの受信バッファの合計がMAX_RECV_BUFFER_MEMORYを超えない範囲で広げる
+            let current = socket.recv_buffer.capacity();
+            let used: usize = table.values().map(|s| s.recv_buffer.capacity()).sum();
+            let size = cmp::min(size, current + MAX_RECV_BUFFER_MEMORY.saturating_sub(used));
+            socket = table
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?;
+            if size > current {
+                dbg!("recv buffer autotuned", current, size);
+                socket.grow_recv_buffer(size);
+            }
+        }
+        if socket.window_update_needed() {
+            // 受信バッファが十分空いたことを相手に知らせる(ウィンドウ更新)
+            dbg!("window update", socket.recv_param.window);
+            socket.send_ack()?;
+        }
         Ok(copy_size)
     }
 
-    /// バッファのデータを送信する．必要であれば複数のパケットに分割して送信する．
-    /// 全て送信したら（まだackされてなくても）リターンする．
+    /// バッファのデータを送信バッファにコピーし，送れる分はすぐに送信する．
+    /// 送信バッファが一杯ならACKで空くまでブロックし，全てコピーしたら（まだ送信されてなくても）リターンする．
     pub fn send(&self, sock_id: SockID, buffer: &[u8]) -> Result<()> {
+        self.send_buffered(sock_id, buffer, false)
+    }
+
+    /// 緊急データを送信する(MSG_OOBに相当)．bufferの最後のバイトが緊急データとして相手に通知される．
+    /// 緊急データはNagleアルゴリズムやSWS回避で保留せずに送る
+    pub fn send_urgent(&self, sock_id: SockID, buffer: &[u8]) -> Result<()> {
+        self.send_buffered(sock_id, buffer, true)
+    }
+
+    /// bufferを送信バッファにコピーして送信する．urgentなら最後のバイトを緊急データにする
+    fn send_buffered(&self, sock_id: SockID, buffer: &[u8], urgent: bool) -> Result<()> {
         let mut cursor = 0;
         while cursor < buffer.len() {
             let mut table = self.sockets.write().unwrap();
-            let mut socket = table
+            let socket = table
                 .get_mut(&sock_id)
                 .context(format!("no such socket: {:?}", sock_id))?;
-            let mut send_size = cmp::min(
-                MSS,
-                cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-            );
-            while send_size == 0 {
-                dbg!("unable to slide send window");
+            socket.check_error()?;
+            match socket.status {
+                TcpStatus::Established | TcpStatus::CloseWait => {}
+                // Fast Openでacceptした接続は，ハンドシェイクの完了前でも応答をバッファに書き込める
+                TcpStatus::SynRcvd if socket.early_accepted => {}
+                _ => anyhow::bail!("cannot send in {} state", socket.status),
+            }
+            let copied = socket.send_buffer.push(&buffer[cursor..]);
+            cursor += copied;
+            // 送信バッファの先頭のseq．Fast Openでacceptした接続のSYNがまだackされていなければ，
+            // データはSYNの次から始まる
+            let mut buffer_seq = socket.send_param.unacked_seq;
+            if buffer_seq == socket.send_param.initial_seq {
+                buffer_seq += 1;
+            }
+            if cursor == buffer.len() {
+                // 書き込みの最後のバイトを含むセグメントにPSHを立てる
+                socket.send_param.push_seq = buffer_seq + socket.send_buffer.len() as u32;
+            }
+            if urgent && cursor == buffer.len() {
+                socket.send_param.urgent_pointer =
+                    Some(buffer_seq + socket.send_buffer.len() as u32);
+                dbg!("urgent pointer", socket.send_param.urgent_pointer);
+            }
+            self.transmit(socket, urgent)?;
+            if copied == 0 {
+                dbg!("send buffer full");
                 // ロックを外してイベントの待機．受信スレッドがロックを取得できるようにするため．
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::Acked);
-                table = self.sockets.write().unwrap();
-                socket = table
-                    .get_mut(&sock_id)
-                    .context(format!("no such socket: {:?}", sock_id))?;
-                // 送信サイズを再計算する
-                send_size = cmp::min(
-                    MSS,
-                    cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-                );
-            }
-            dbg!("current window size", socket.send_param.window);
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &buffer[cursor..cursor + send_size],
-            )?;
-            cursor += send_size;
-            socket.send_param.next += send_size as u32;
-            socket.send_param.window -= send_size as u16;
-            // 少しの間ロックを外して待機し，受信スレッドがACKを受信できるようにしている．
-            // send_windowが0になるまで送り続け，送信がブロックされる確率を下げるため
-            drop(table);
-            thread::sleep(Duration::from_millis(1));
+                self.wait_any_event(table, None);
+            }
         }
         Ok(())
     }
 
-    /// 接続を閉じる．
+    /// 帯域外で受信した緊急データを読み込む(MSG_OOBに相当)．ブロックせず，まだなければNoneを返す
+    pub fn recv_urgent(&self, sock_id: SockID) -> Result<Option<u8>> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        Ok(socket.urgent_data.take())
+    }
+
+    /// 接続を閉じる．FINを送っていなければ送り，相手のFINを受信して接続が閉じるまでブロックする．
+    /// TIME_WAITに入ったソケットは2MSL経ってからタイマーが破棄する．
+    /// lingerが設定されていれば自分のFINがackされるまでだけ待ち，時間内にackされなければRSTで切ってエラーを返す．
+    /// ackされた後も，相手のFINを受信してTIME_WAITを抜けるまでの手順はcloseから戻った後に続ける．
+    /// lingerが0なら送信待ちのデータを捨ててすぐにRSTで切る
     pub fn close(&self, sock_id: SockID) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.status == TcpStatus::Listen {
+            table.remove(&sock_id);
+            return Ok(());
+        }
+        if socket.linger == Some(Duration::from_secs(0)) {
+            self.reset(table, sock_id)?;
+            return Ok(());
+        }
+        let linger = socket.linger;
+        let deadline = linger.map(|linger| Instant::now() + linger);
+        drop(table);
+        if let Err(error) = self.send_fin(sock_id, deadline) {
+            // 異常終了した接続やlingerの時間内に送信し終わらなかった接続はFINを送らない
+            dbg!(error);
+        }
+        loop {
+            let mut table = self.sockets.write().unwrap();
+            let socket = table
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?;
+            let closed = match socket.status {
+                TcpStatus::Established
+                | TcpStatus::CloseWait
+                | TcpStatus::FinWait1
+                | TcpStatus::Closing
+                | TcpStatus::LastAck => false,
+                TcpStatus::FinWait2 => {
+                    if linger.is_some() {
+                        // lingerで待つのは自分のFINがackされるまで．相手のFINを待つ残りの手順は続け，
+                        // FIN_WAIT2_TIMEOUTまでに届かなければタイマーが破棄する
+                        socket.released = true;
+                        socket.close_deadline =
+                            Some(SystemTime::now() + Duration::from_secs(FIN_WAIT2_TIMEOUT));
+                        dbg!("closed", sock_id);
+                        return Ok(());
+                    }
+                    false
+                }
+                _ => true,
+            };
+            if socket.status == TcpStatus::TimeWait
+                && matches!(socket.close_deadline, Some(deadline) if deadline > SystemTime::now())
+            {
+                // 再送されたFINにACKを返せるように，2MSLの間はTIME_WAITのまま残す．破棄はタイマーが行う
+                socket.released = true;
+                dbg!("closed", sock_id);
+                return Ok(());
+            }
+            if closed {
+                release_half_open(&mut table, sock_id);
+                table.remove(&sock_id);
+                dbg!("closed & removed", sock_id);
+                return Ok(());
+            }
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                self.reset(table, sock_id)?;
+                anyhow::bail!("linger timed out: {:?}", sock_id);
+            }
+            // ロックを外してイベントの待機．受信スレッドがロックを取得できるようにするため．
+            self.wait_any_event(table, deadline);
+        }
+    }
+
+    /// 送信待ちのデータを捨て，RSTを送って接続を切る(abortive close)．ソケットは破棄する
+    fn reset(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+    ) -> Result<()> {
+        release_half_open(&mut table, sock_id);
+        let mut socket = table
+            .remove(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        match socket.status {
+            TcpStatus::SynSent | TcpStatus::Closed | TcpStatus::TimeWait => {}
+            _ => socket.send_reset()?,
+        }
+        dbg!("reset & removed", sock_id);
+        Ok(())
+    }
+
+    /// 接続の送信側と受信側を個別に閉じる(half-close)．
+    /// Writeなら送信バッファのデータを全て送信してからFINを送り，以降は送信できなくなるが受信はできる．
+    /// Readなら以降のrecvは0を返し，受信したデータは捨てる
+    pub fn shutdown(&self, sock_id: SockID, how: Shutdown) -> Result<()> {
+        if how != Shutdown::Write {
+            let mut table = self.sockets.write().unwrap();
+            let socket = table
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?;
+            socket.read_shutdown = true;
+            discard_received_data(socket);
+            // recvで待っている操作を起こす
+            self.publish_event(sock_id, TCPEventKind::DataArrived);
+        }
+        if how == Shutdown::Read {
+            return Ok(());
+        }
+        self.send_fin(sock_id, None)
+    }
+
+    /// 送信バッファのデータを全て送信してからFINを送る．
+    /// deadlineまでに送信し終わらなければFINを送らずにエラーを返す
+    fn send_fin(&self, sock_id: SockID, deadline: Option<Instant>) -> Result<()> {
         let mut table = self.sockets.write().unwrap();
         let mut socket = table
             .get_mut(&sock_id)
             .context(format!("no such socket: {:?}", sock_id))?;
+        match socket.status {
+            TcpStatus::Established | TcpStatus::CloseWait => {}
+            _ => return socket.check_error(),
+        }
+        self.transmit(socket, true)?;
+        while socket.unsent_size() > 0 {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                anyhow::bail!("timed out sending buffered data: {:?}", sock_id);
+            }
+            self.wait_any_event(table, deadline);
+            table = self.sockets.write().unwrap();
+            socket = table
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?;
+            self.transmit(socket, true)?;
+        }
+        socket.check_error()?;
         socket.send_tcp_packet(
             socket.send_param.next,
             socket.recv_param.next,
@@ -279,39 +598,327 @@ impl TCP {
             &[],
         )?;
         socket.send_param.next += 1;
+        socket.status = match socket.status {
+            TcpStatus::CloseWait => TcpStatus::LastAck,
+            _ => TcpStatus::FinWait1,
+        };
+        dbg!("sent FIN, status:", &socket.status);
+        Ok(())
+    }
+
+    /// ソケットの輻輳制御アルゴリズムを名前で指定する(LinuxのTCP_CONGESTIONに相当)．
+    /// 接続の途中で切り替えた場合は，輻輳ウィンドウとスロースタート閾値を引き継ぐ．
+    /// リスニングソケットに指定した場合は，acceptされるソケットに引き継がれる
+    pub fn set_congestion_control(&self, sock_id: SockID, name: &str) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        let mut congestion = congestion::from_name(name)?;
+        congestion.set_window(socket.congestion.cwnd(), socket.congestion.ssthresh());
+        socket.congestion = congestion;
+        dbg!("congestion control", sock_id, name);
+        Ok(())
+    }
+
+    /// 送信したデータがackされないまま許容する時間を指定する(TCP_USER_TIMEOUTに相当)．
+    /// 指定すると再送回数の上限の代わりに使われる．advertiseならUser Timeoutオプション(RFC5482)で相手に知らせる．
+    /// リスニングソケットに指定した場合は，acceptされるソケットに引き継がれる
+    pub fn set_user_timeout(
+        &self,
+        sock_id: SockID,
+        timeout: Option<Duration>,
+        advertise: bool,
+    ) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.user_timeout = timeout;
+        socket.advertise_user_timeout = advertise;
+        socket.user_timeout_pending = advertise && timeout.is_some();
+        Ok(())
+    }
+
+    /// 相手がUser Timeoutオプションで広告した値を採用するかを指定する(RFC5482のChangeable)．
+    /// 既定では採用せず，採用する場合もset_user_timeoutで自分の値を指定していればそちらを優先する．
+    /// リスニングソケットに指定した場合は，acceptされるソケットに引き継がれる
+    pub fn set_accept_user_timeout(&self, sock_id: SockID, accept: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.accept_remote_user_timeout = accept;
+        Ok(())
+    }
+
+    /// closeの動作を指定する(SO_LINGERに相当)．Some(timeout)ならcloseは自分のFINがackされるまで
+    /// 最大timeoutだけ待ち，Some(0)なら送信待ちのデータを捨ててRSTで接続を切る．
+    /// リスニングソケットに指定した場合は，acceptされるソケットに引き継がれる
+    pub fn set_linger(&self, sock_id: SockID, linger: Option<Duration>) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.linger = linger;
+        Ok(())
+    }
+
+    /// 緊急データを帯域外ではなく通常のデータと一緒に読むかどうかを指定する(SO_OOBINLINEに相当)
+    pub fn set_oob_inline(&self, sock_id: SockID, oob_inline: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.oob_inline = oob_inline;
+        Ok(())
+    }
+
+    /// キープアライブの設定をする(SO_KEEPALIVEに相当)．Noneなら無効にする．
+    /// リスニングソケットに指定した場合は，acceptされるソケットに引き継がれる
+    pub fn set_keepalive(&self, sock_id: SockID, keepalive: Option<Keepalive>) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.keepalive = keepalive;
+        socket.keepalive_probes = 0;
+        Ok(())
+    }
+
+    /// Nagleアルゴリズムを無効にするかどうかを指定する(TCP_NODELAYに相当)．
+    /// 無効にした時点で保留しているデータは送信する
+    pub fn set_nodelay(&self, sock_id: SockID, nodelay: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.nodelay = nodelay;
+        if nodelay {
+            self.transmit(socket, false)?;
+        }
+        Ok(())
+    }
+
+    /// ECN(RFC3168)を使うかを指定する(Linuxのnet.ipv4.tcp_ecn=1に相当)．
+    /// 以降にconnectする接続はSYNでECNを要求し，リスニングソケットはECNを要求するSYNに応じる．
+    /// ECNはSYNで交渉するのでconnectの前に決めておく必要があり，ソケット毎ではなくこのTCPの全てのソケットに効く．
+    /// 確立済みの接続には影響しない
+    pub fn set_ecn(&self, ecn: bool) {
+        self.ecn.store(ecn, Ordering::Relaxed);
+    }
+
+    /// 半開きの接続が溢れた時にSYN cookie(RFC4987)を使うかどうかを指定する(Linuxのnet.ipv4.tcp_syncookies=1に相当)．
+    /// リスニングソケットに指定する
+    pub fn set_syn_cookies(&self, sock_id: SockID, syn_cookies: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.syn_cookies = syn_cookies;
+        Ok(())
+    }
+
+    /// TCP Fast Open(RFC7413)のSYNを受け付けるかどうかを指定する(LinuxのTCP_FASTOPENに相当)．
+    /// リスニングソケットに指定する．正しいcookieの付いたSYNのデータは，最後のACKを待たずにacceptで読める
+    pub fn set_fast_open(&self, sock_id: SockID, fast_open: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.fast_open = fast_open;
+        Ok(())
+    }
+
+    /// peerとの接続のセグメントをMD5署名(RFC2385)で認証する鍵を設定する(LinuxのTCP_MD5SIGに相当)．
+    /// リスニングソケットに指定し，peerから受け入れる接続に使う．Noneなら鍵を削除する
+    pub fn set_md5_key(&self, sock_id: SockID, peer: Ipv4Addr, key: Option<&[u8]>) -> Result<()> {
+        check_md5_key(key)?;
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.status != TcpStatus::Listen {
+            anyhow::bail!("md5 keys can only be set on listening sockets");
+        }
+        match key {
+            Some(key) => socket.md5_keys.insert(peer, key.to_vec()),
+            None => socket.md5_keys.remove(&peer),
+        };
+        Ok(())
+    }
+
+    /// connectでpeerに接続する時に使うMD5署名の鍵を設定する．Noneなら鍵を削除する．
+    /// 接続済みのソケットの鍵は変わらない
+    pub fn set_connect_md5_key(&self, peer: Ipv4Addr, key: Option<&[u8]>) -> Result<()> {
+        check_md5_key(key)?;
+        let mut md5_keys = self.md5_keys.lock().unwrap();
+        match key {
+            Some(key) => md5_keys.insert(peer, key.to_vec()),
+            None => md5_keys.remove(&peer),
+        };
+        Ok(())
+    }
+
+    /// connectでpeerに接続する時に使う輻輳制御アルゴリズムを名前で指定する．Noneならデフォルトに戻す．
+    /// ECNが必須のアルゴリズムならset_ecnに関わらずSYNでECNを要求する．
+    /// 接続済みのソケットのアルゴリズムは変わらない
+    pub fn set_connect_congestion_control(&self, peer: Ipv4Addr, name: Option<&str>) -> Result<()> {
+        let mut connect_congestion = self.connect_congestion.lock().unwrap();
+        match name {
+            Some(name) => {
+                congestion::from_name(name)?;
+                connect_congestion.insert(peer, name.to_string())
+            }
+            None => connect_congestion.remove(&peer),
+        };
+        Ok(())
+    }
+
+    /// 送信バッファの未送信のデータをセグメントに分割して送信する．ACKの受信時とタイマーから呼ばれる．
+    /// ウィンドウに加えてNagleアルゴリズム，SWS回避，ペーシングで送信を保留する．
+    /// forceならNagleアルゴリズムとSWS回避による保留はしない
+    fn transmit(&self, socket: &mut Socket, force: bool) -> Result<()> {
         match socket.status {
-            TcpStatus::Established => {
-                socket.status = TcpStatus::FinWait1;
-                // ロックを外してイベントの待機．受信スレッドがロックを取得できるようにするため．
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
+            TcpStatus::Established | TcpStatus::CloseWait => {}
+            _ => return Ok(()),
+        }
+        loop {
+            let unsent = socket.unsent_size();
+            if unsent == 0 {
+                socket.send_param.sws_deadline = None;
+                break;
             }
-            TcpStatus::CloseWait => {
-                socket.status = TcpStatus::LastAck;
-                // ロックを外してイベントの待機．受信スレッドがロックを取得できるようにするため．
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
+            if !force
+                && !socket.nodelay
+                && unsent < socket.mss()
+                && socket.send_param.flight_size() > 0
+            {
+                // Nagleアルゴリズム．未ackのデータがある間はMSS未満のセグメントを送らずに溜めておき，
+                // ACKを受信した時にまとめて送信する
+                dbg!("nagle: hold", unsent);
+                break;
             }
-            TcpStatus::Listen => {
-                table.remove(&sock_id);
+            let now = SystemTime::now();
+            if socket.send_param.next_send_time > now {
+                // ペーシング．続きはタイマーから送る
+                break;
+            }
+            let mut send_size = sendable_size(socket, unsent);
+            if send_size == 0 {
+                let usable = cmp::min(socket.mss(), cmp::min(usable_window(socket), unsent));
+                if usable == 0 {
+                    dbg!("unable to slide send window");
+                    start_persist_timer(socket);
+                    break;
+                }
+                if !force {
+                    // SWS回避で保留している．送信中のセグメントがなくACKが来る見込みがなければ，
+                    // SWS_OVERRIDE_TIMEOUT待ってもウィンドウが開かない時に小さくても送る
+                    if socket.send_param.flight_size() > 0 {
+                        break;
+                    }
+                    let deadline = *socket
+                        .send_param
+                        .sws_deadline
+                        .get_or_insert(now + Duration::from_millis(SWS_OVERRIDE_TIMEOUT));
+                    if deadline > now {
+                        break;
+                    }
+                    dbg!("sws override", usable);
+                }
+                send_size = usable;
+            }
+            socket.send_param.sws_deadline = None;
+            dbg!(
+                "current window size",
+                socket.send_param.window,
+                socket.congestion.cwnd()
+            );
+            let mut payload = vec![0; send_size];
+            socket
+                .send_buffer
+                .peek(socket.send_param.flight_size() as usize, &mut payload);
+            let seq = socket.send_param.next;
+            let mut flag = tcpflags::ACK;
+            if socket.send_param.cwr_pending {
+                // ECEに応答してウィンドウを縮めたことを知らせる
+                flag |= tcpflags::CWR;
+                socket.send_param.cwr_pending = false;
+            }
+            if seq < socket.send_param.push_seq
+                && socket.send_param.push_seq <= seq + send_size as u32
+            {
+                flag |= tcpflags::PSH;
+            }
+            socket.send_tcp_packet(seq, socket.recv_param.next, flag, &payload)?;
+            socket.send_param.next += send_size as u32;
+            if let Some(interval) = pacing_interval(socket, send_size) {
+                // 前回の送信予定時刻から間隔を空ける．タイマーの周期分の遅れは取り戻せるようにする
+                let param = &mut socket.send_param;
+                param.next_send_time = cmp::max(
+                    param.next_send_time,
+                    now - Duration::from_millis(TIMER_INTERVAL),
+                ) + interval;
             }
+        }
+        Ok(())
+    }
+
+    /// キープアライブ．idle時間何も受信しなければinterval毎にプローブを送り，
+    /// count個送っても応答がなければ接続を異常終了させる
+    fn keepalive(&self, socket: &mut Socket) -> Result<()> {
+        let keepalive = match socket.keepalive {
+            Some(keepalive) => keepalive,
+            None => return Ok(()),
+        };
+        match socket.status {
+            TcpStatus::Established | TcpStatus::CloseWait => {}
             _ => return Ok(()),
         }
+        if !socket.retransmission_queue.is_empty() || !socket.send_buffer.is_empty() {
+            // 送信中のデータがあれば再送で相手の消失を検出できる
+            return Ok(());
+        }
+        let idle = socket.last_received.elapsed().unwrap_or_default();
+        if idle < keepalive.idle + keepalive.interval * socket.keepalive_probes as u32 {
+            return Ok(());
+        }
+        if socket.keepalive_probes >= keepalive.count {
+            dbg!("keepalive timeout");
+            self.abort(socket, SocketError::TimedOut);
+            return Ok(());
+        }
+        dbg!("keepalive probe", socket.keepalive_probes);
+        socket.send_probe()?;
+        socket.keepalive_probes += 1;
         Ok(())
     }
 
-    /// 指定したソケットIDと種別のイベントを待機
+    /// 接続を異常終了させてCLOSED状態にする．ブロックしている操作はerrorを返して戻る
+    fn abort(&self, socket: &mut Socket, error: SocketError) {
+        dbg!("abort", socket.get_sock_id(), error);
+        socket.status = TcpStatus::Closed;
+        socket.error = Some(error);
+        socket.retransmission_queue.clear();
+        let buffered = socket.send_buffer.len();
+        socket.send_buffer.consume(buffered);
+        socket.send_param.unacked_seq = socket.send_param.next;
+        socket.send_param.persist_deadline = None;
+        socket.recv_param.ack_deadline = None;
+        self.publish_event(socket.get_sock_id(), TCPEventKind::Aborted);
+    }
+
+    /// 指定したソケットIDと種別のイベントを待機．接続が異常終了した場合も戻る
     fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) {
         let (lock, cvar) = &self.event_condvar;
         let mut event = lock.lock().unwrap();
         loop {
             if let Some(ref e) = *event {
+                if e.sock_id == sock_id && e.kind == TCPEventKind::Aborted {
+                    // 同じソケットを待っている他の操作も起こすので消費しない
+                    return;
+                }
                 if e.sock_id == sock_id && e.kind == kind {
                     break;
                 }
@@ -323,6 +930,28 @@ impl TCP {
         *event = None;
     }
 
+    /// テーブルのロックを外し，いずれかのソケットにイベントが発行されるかdeadlineになるまで待機する．
+    /// 戻ったら呼び出し側でソケットの状態を確認し直す．イベントはテーブルのロックを持ったまま発行されるので，
+    /// ロックを外す前にeventのロックを取っておけば，状態を確認してから待機するまでの間のイベントを取りこぼさない
+    fn wait_any_event(
+        &self,
+        table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        deadline: Option<Instant>,
+    ) {
+        let (lock, cvar) = &self.event_condvar;
+        let event = lock.lock().unwrap();
+        drop(table);
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now < deadline {
+                    drop(cvar.wait_timeout(event, deadline - now).unwrap());
+                }
+            }
+            None => drop(cvar.wait(event).unwrap()),
+        }
+    }
+
     /// 受信スレッド用の関数．
     fn receive_handler(&self) -> Result<()> {
         dbg!("begin recv thread");
@@ -338,6 +967,7 @@ impl TCP {
                 Err(_) => continue,
             };
             let local_addr = packet.get_destination();
+            let ce = packet.get_ecn() == ECN_CE;
             // pnetのTcpPacketを生成
             let tcp_packet = match TcpPacket::new(packet.payload()) {
                 Some(p) => p,
@@ -375,14 +1005,36 @@ impl TCP {
                 dbg!("invalid checksum");
                 continue;
             }
+            if !packet.is_correct_md5(local_addr, remote_addr, socket.md5_key_for(remote_addr)) {
+                // 署名のない，または署名の正しくないセグメントは，RSTもACKも返さずに捨てる(RFC2385)
+                dbg!("invalid md5 signature");
+                continue;
+            }
             let sock_id = socket.get_sock_id();
+            if packet.get_flag() & tcpflags::RST > 0 {
+                self.reset_handler(table, sock_id, &packet);
+                continue;
+            }
+            if packet.get_flag() & tcpflags::SYN > 0 && is_synchronized(&socket.status) {
+                // 接続済みの4タプルへのSYNでは接続を作り直さず，現在の状態をACKで知らせる
+                dbg!("syn in synchronized state", &socket.status);
+                if let Err(error) = self.send_challenge_ack(socket) {
+                    dbg!(error);
+                }
+                continue;
+            }
             if let Err(error) = match socket.status {
                 TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
-                TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
+                TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet, ce),
                 TcpStatus::SynSent => self.synsent_handler(socket, &packet),
-                TcpStatus::Established => self.established_handler(socket, &packet),
-                TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
-                TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
+                TcpStatus::Established => self.established_handler(socket, &packet, ce),
+                TcpStatus::CloseWait | TcpStatus::LastAck => {
+                    self.close_handler(socket, &packet, ce)
+                }
+                TcpStatus::FinWait1
+                | TcpStatus::FinWait2
+                | TcpStatus::Closing
+                | TcpStatus::TimeWait => self.finwait_handler(socket, &packet, ce),
                 _ => {
                     dbg!("not implemented state");
                     Ok(())
@@ -393,6 +1045,70 @@ impl TCP {
         }
     }
 
+    /// RSTを受信した時の処理．seqがrecv_param.nextと一致するRSTであれば接続を異常終了させる．
+    /// 一致しないが受信ウィンドウ内にあるRSTにはチャレンジACKを返す(RFC5961 3.2)．
+    /// SYNSENTではSYNをackするRSTのみ受け付ける
+    fn reset_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) {
+        let socket = table.get_mut(&sock_id).unwrap();
+        let acceptable = match socket.status {
+            TcpStatus::Listen | TcpStatus::Closed => false,
+            TcpStatus::SynSent => {
+                packet.get_flag() & tcpflags::ACK > 0 && packet.get_ack() == socket.send_param.next
+            }
+            _ if packet.get_seq() == socket.recv_param.next => true,
+            _ => {
+                if is_in_recv_window(socket, packet.get_seq()) {
+                    // 推測されたseqによるRSTかもしれないので，正しい相手にseqを合わせて送り直してもらう
+                    if let Err(error) = self.send_challenge_ack(socket) {
+                        dbg!(error);
+                    }
+                }
+                false
+            }
+        };
+        if !acceptable {
+            dbg!("unacceptable reset", packet.get_seq());
+            return;
+        }
+        release_half_open(&mut table, sock_id);
+        let socket = table.get_mut(&sock_id).unwrap();
+        match socket.status {
+            TcpStatus::SynSent => self.abort(socket, SocketError::ConnectionRefused),
+            TcpStatus::SynRcvd if socket.early_accepted => {
+                self.abort(socket, SocketError::ConnectionReset)
+            }
+            TcpStatus::SynRcvd => {
+                // まだacceptされていないので，リスニングソケットに戻る代わりに破棄する
+                table.remove(&sock_id);
+                dbg!("reset & removed", sock_id);
+            }
+            _ => self.abort(socket, SocketError::ConnectionReset),
+        }
+    }
+
+    /// チャレンジACK(RFC5961)を送る．正しい相手であれば，現在のseqとackを知って
+    /// 必要ならそれに合わせたRSTを送り直してくる．
+    /// 攻撃者に大量のACKを送らせないように，全ソケットで1秒間に送る数を制限する(RFC5961 7)
+    fn send_challenge_ack(&self, socket: &mut Socket) -> Result<()> {
+        let mut challenge_acks = self.challenge_acks.lock().unwrap();
+        if challenge_acks.0.elapsed() >= Duration::from_secs(1) {
+            *challenge_acks = (Instant::now(), challenge_ack_limit());
+        }
+        if challenge_acks.1 == 0 {
+            dbg!("challenge ack limit exceeded");
+            return Ok(());
+        }
+        challenge_acks.1 -= 1;
+        drop(challenge_acks);
+        dbg!("challenge ack", socket.get_sock_id());
+        socket.send_ack()
+    }
+
     /// LISTEN状態のソケットに到着したパケットの処理
     fn listen_handler(
         &self,
@@ -402,12 +1118,48 @@ impl TCP {
         remote_addr: Ipv4Addr,
     ) -> Result<()> {
         dbg!("listen handler");
+        let sock_id = SockID(
+            listening_socket_id.0,
+            remote_addr,
+            listening_socket_id.2,
+            packet.get_src(),
+        );
         if packet.get_flag() & tcpflags::ACK > 0 {
-            // 本来ならRSTをsendする
+            if packet.get_flag() & tcpflags::SYN == 0
+                && self.syn_cookie_handler(&mut table, listening_socket_id, sock_id, packet)?
+            {
+                return Ok(());
+            }
+            // 半開きの接続が破棄された後のACKなど，知らない接続へのACKにはRSTを返す(RFC793)
+            let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+            socket::send_stateless_packet(
+                &mut listening_socket.sender,
+                sock_id,
+                packet.get_ack(),
+                0,
+                tcpflags::RST,
+                listening_socket
+                    .md5_keys
+                    .get(&remote_addr)
+                    .map(|key| key.as_slice()),
+            )?;
             return Ok(());
         }
         let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+        let half_open = listening_socket.half_open;
         if packet.get_flag() & tcpflags::SYN > 0 {
+            if accept_queue_full(listening_socket) {
+                // 接続しても受け入れられないのでSYNを捨て，クライアントの再送を待つ
+                dbg!("accept queue full", listening_socket_id);
+                return Ok(());
+            }
+            if half_open >= listening_socket.backlog {
+                if listening_socket.syn_cookies {
+                    return self.send_syn_cookie(listening_socket, sock_id, packet);
+                }
+                dbg!("syn backlog full", half_open);
+                return Ok(());
+            }
             // passive openの処理
             // 後に接続済みソケットとなるソケットを新たに生成する
             let mut connection_socket = Socket::new(
@@ -420,40 +1172,220 @@ impl TCP {
             connection_socket.recv_param.next = packet.get_seq() + 1;
             connection_socket.recv_param.initial_seq = packet.get_seq();
             connection_socket.send_param.initial_seq = rand::thread_rng().gen_range(1..1 << 31);
-            connection_socket.send_param.window = packet.get_window_size();
+            connection_socket.send_param.window = connection_socket.remote_window(packet);
+            connection_socket.send_param.max_window = connection_socket.send_param.window;
+            connection_socket.send_param.window_update_seq = packet.get_seq();
+            // SYN/ACKのオプションとECNの交渉に使うので，送信前に引き継ぐ
+            connection_socket.inherit(listening_socket)?;
+            connection_socket.process_options(packet);
+            let mut flag = tcpflags::SYN | tcpflags::ACK;
+            if (self.ecn.load(Ordering::Relaxed) || connection_socket.congestion.needs_ecn())
+                && packet.get_flag() & (tcpflags::ECE | tcpflags::CWR)
+                    == tcpflags::ECE | tcpflags::CWR
+            {
+                // ECN-setup SYNにはECN-setup SYN-ACKで応じる
+                connection_socket.ecn = true;
+                flag |= tcpflags::ECE;
+            }
+            if listening_socket.fast_open {
+                self.process_fast_open(&mut connection_socket, packet, remote_addr);
+            }
             connection_socket.send_tcp_packet(
                 connection_socket.send_param.initial_seq,
                 connection_socket.recv_param.next,
-                tcpflags::SYN | tcpflags::ACK,
+                flag,
                 &[],
             )?;
             connection_socket.send_param.next = connection_socket.send_param.initial_seq + 1;
             connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
-            connection_socket.listening_socket = Some(listening_socket.get_sock_id());
             dbg!("status: listen -> ", &connection_socket.status);
-            table.insert(connection_socket.get_sock_id(), connection_socket);
+            let early_accepted = connection_socket.early_accepted;
+            connection_socket.counted_half_open = true;
+            listening_socket.half_open += 1;
+            table.insert(sock_id, connection_socket);
+            if early_accepted {
+                // SYNのデータをすぐに読めるように，最後のACKを待たずにacceptキューに入れる
+                let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+                listening_socket
+                    .connected_connection_queue
+                    .push_back(sock_id);
+                self.publish_event(listening_socket_id, TCPEventKind::ConnectionCompleted);
+            }
         }
         Ok(())
     }
 
+    /// SYNのFast Openオプションを処理する(RFC7413)．cookieが正しければSYNのデータを受信バッファに入れ，
+    /// 最後のACKを待たずにacceptできるようにする．cookieの要求か，正しくないcookieには
+    /// SYN/ACKで新しいcookieを返す．その場合SYNのデータはackされず，クライアントが接続後に送り直す
+    fn process_fast_open(&self, socket: &mut Socket, packet: &TCPPacket, remote_addr: Ipv4Addr) {
+        let cookie = match packet
+            .get_option(tcpoptions::FAST_OPEN)
+            .and_then(tcpoptions::parse_fast_open)
+        {
+            Some(cookie) => cookie,
+            None => return,
+        };
+        if !cookie.is_empty() && fastopen::validate(self.fast_open_secret, remote_addr, cookie) {
+            let copied = socket.recv_buffer.push(packet.payload());
+            socket.recv_param.next += copied as u32;
+            socket.recv_param.window -= copied as u32;
+            socket.early_accepted = true;
+            dbg!("fast open accepted", copied);
+            return;
+        }
+        dbg!("fast open cookie requested or rejected", cookie.len());
+        socket.fast_open_cookie =
+            Some(fastopen::generate(self.fast_open_secret, remote_addr).to_vec());
+    }
+
+    /// 半開きの接続が溢れている時に，ソケットを生成せずにSYN cookieを初期seqとしたSYN/ACKを返す．
+    /// SYNのオプションは最後のACKまで覚えておけないので，MSS以外は使わない
+    fn send_syn_cookie(
+        &self,
+        listening_socket: &mut Socket,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        let mss = packet
+            .get_option(tcpoptions::MSS)
+            .and_then(tcpoptions::parse_mss)
+            .unwrap_or(tcpoptions::DEFAULT_MSS);
+        let cookie =
+            match syncookie::generate(self.syn_cookie_secret, sock_id, packet.get_seq(), mss) {
+                Some(cookie) => cookie,
+                None => {
+                    // cookieに載せられるどのMSSよりも小さいので，溢れた時と同じくSYNを捨てる
+                    dbg!("mss too small for syn cookie", mss);
+                    return Ok(());
+                }
+            };
+        dbg!("send syn cookie", sock_id, cookie);
+        listening_socket.syn_cookie_sent = Some(SystemTime::now());
+        socket::send_stateless_packet(
+            &mut listening_socket.sender,
+            sock_id,
+            cookie,
+            packet.get_seq() + 1,
+            tcpflags::SYN | tcpflags::ACK,
+            listening_socket
+                .md5_keys
+                .get(&sock_id.1)
+                .map(|key| key.as_slice()),
+        )?;
+        Ok(())
+    }
+
+    /// リスニングソケットに届いたACKを，SYN cookieを使ったハンドシェイクの最後のACKとして処理する．
+    /// ackされたcookieが正しければ，SYNRCVDを経ずに接続済みソケットを生成してtrueを返す
+    fn syn_cookie_handler(
+        &self,
+        table: &mut HashMap<SockID, Socket>,
+        listening_socket_id: SockID,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<bool> {
+        let listening_socket = table.get(&listening_socket_id).unwrap();
+        // 最近SYN cookieを送っていなければ，cookieを推測したACKの可能性があるので検証しない
+        let recent = match listening_socket.syn_cookie_sent {
+            Some(time) => time.elapsed().unwrap_or_default() < syncookie::MAX_COOKIE_AGE,
+            None => false,
+        };
+        if !listening_socket.syn_cookies || !recent {
+            return Ok(false);
+        }
+        let peer_seq = packet.get_seq().wrapping_sub(1);
+        let cookie = packet.get_ack().wrapping_sub(1);
+        let mss = match syncookie::validate(self.syn_cookie_secret, sock_id, peer_seq, cookie) {
+            Some(mss) => mss,
+            None => {
+                dbg!("invalid syn cookie", sock_id);
+                return Ok(false);
+            }
+        };
+        if accept_queue_full(listening_socket) {
+            // 最後のACKを捨てる．クライアントがデータかACKを再送すればまた検証する
+            dbg!("accept queue full", listening_socket_id);
+            return Ok(true);
+        }
+        let mut socket = Socket::new(
+            sock_id.0,
+            sock_id.1,
+            sock_id.2,
+            sock_id.3,
+            TcpStatus::Established,
+        )?;
+        socket.inherit(listening_socket)?;
+        socket.remote_mss = mss;
+        socket.recv_param.initial_seq = peer_seq;
+        socket.recv_param.next = packet.get_seq();
+        socket.send_param.initial_seq = cookie;
+        socket.send_param.unacked_seq = packet.get_ack();
+        socket.send_param.next = packet.get_ack();
+        socket.send_param.window = socket.remote_window(packet);
+        socket.send_param.max_window = socket.send_param.window;
+        socket.send_param.window_update_seq = packet.get_seq();
+        socket.send_param.window_update_ack = packet.get_ack();
+        socket.process_options(packet);
+        dbg!("syn cookie accepted", sock_id, mss);
+        if !packet.payload().is_empty() {
+            self.process_payload(&mut socket, packet)?;
+        }
+        table.insert(sock_id, socket);
+        let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+        listening_socket
+            .connected_connection_queue
+            .push_back(sock_id);
+        self.publish_event(listening_socket_id, TCPEventKind::ConnectionCompleted);
+        Ok(true)
+    }
+
     /// SYNRCVD状態のソケットに到着したパケットの処理
     fn synrcvd_handler(
         &self,
         mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
         sock_id: SockID,
         packet: &TCPPacket,
+        ce: bool,
     ) -> Result<()> {
         dbg!("synrcvd handler");
+        // Fast Openでacceptキューに入れた接続は，キューが溢れていても確立させる
+        let queue_full = match table[&sock_id].listening_socket {
+            Some(_) if table[&sock_id].early_accepted => false,
+            Some(id) => matches!(table.get(&id), Some(ls) if accept_queue_full(ls)),
+            None => false,
+        };
         let socket = table.get_mut(&sock_id).unwrap();
 
+        if packet.get_flag() & tcpflags::SYN > 0 && packet.get_flag() & tcpflags::ACK == 0 {
+            if packet.get_seq() == socket.recv_param.initial_seq {
+                // SYNの再送．SYN/ACKが失われたので，同じ初期seqのSYN/ACKを再送する
+                dbg!("retransmitted syn", sock_id);
+                return socket.resend_unacked_segment();
+            }
+            // 別の初期seqのSYN．半開きの接続は作り直さない
+            return self.send_challenge_ack(socket);
+        }
         if packet.get_flag() & tcpflags::ACK > 0
             && socket.send_param.unacked_seq <= packet.get_ack()
             && packet.get_ack() <= socket.send_param.next
         {
+            if queue_full {
+                // 最後のACKを捨ててSYNRCVDのままにする．SYN/ACKの再送に対してクライアントがACKを再送する
+                dbg!("accept queue full", sock_id);
+                return Ok(());
+            }
+            process_options_and_ecn(socket, packet, ce);
             socket.recv_param.next = packet.get_seq();
             socket.send_param.unacked_seq = packet.get_ack();
             socket.status = TcpStatus::Established;
             dbg!("status: synrcvd ->", &socket.status);
+            release_half_open(&mut table, sock_id);
+            let socket = table.get_mut(&sock_id).unwrap();
+            if socket.early_accepted {
+                // 既にacceptされているので，ハンドシェイクの完了までに書き込まれた応答を送る
+                return self.transmit(socket, false);
+            }
             if let Some(id) = socket.listening_socket {
                 let ls = table.get_mut(&id).unwrap();
                 ls.connected_connection_queue.push_back(sock_id);
@@ -471,12 +1403,21 @@ impl TCP {
             && packet.get_ack() <= socket.send_param.next
             && packet.get_flag() & tcpflags::SYN > 0
         {
+            socket.process_options(packet);
             socket.recv_param.next = packet.get_seq() + 1;
             socket.recv_param.initial_seq = packet.get_seq();
             socket.send_param.unacked_seq = packet.get_ack();
-            socket.send_param.window = packet.get_window_size();
+            socket.send_param.window = socket.remote_window(packet);
+            socket.send_param.max_window = socket.send_param.window;
+            socket.send_param.window_update_seq = packet.get_seq();
+            socket.send_param.window_update_ack = packet.get_ack();
+            // ECN-setup SYN-ACKが返ってこなければECNは使わない
+            socket.ecn &= packet.get_flag() & (tcpflags::ECE | tcpflags::CWR) == tcpflags::ECE;
             if socket.send_param.unacked_seq > socket.send_param.initial_seq {
                 socket.status = TcpStatus::Established;
+                if socket.fast_open_cookie.is_some() {
+                    self.process_fast_open_reply(socket, packet);
+                }
                 socket.send_tcp_packet(
                     socket.send_param.next,
                     socket.recv_param.next,
@@ -484,6 +1425,8 @@ impl TCP {
                     &[],
                 )?;
                 dbg!("status: synsent ->", &socket.status);
+                // SYNに載せられなかったデータを送る
+                self.transmit(socket, false)?;
                 self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
             } else {
                 socket.status = TcpStatus::SynRcvd;
@@ -499,38 +1442,250 @@ impl TCP {
         Ok(())
     }
 
-    fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) {
-        dbg!("ack accept", socket.send_param.unacked_seq);
-        while let Some(item) = socket.retransmission_queue.pop_front() {
-            if socket.send_param.unacked_seq > item.packet.get_seq() {
-                // ackされてるので除去
-                dbg!("successfully acked", item.packet.get_seq());
-                socket.send_param.window += item.packet.payload().len() as u16;
-                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
-            } else {
-                // ackされてない．戻す．
-                socket.retransmission_queue.push_front(item);
+    /// Fast OpenのSYNに対するSYN/ACKを処理する．返ってきたcookieを覚えておき，
+    /// SYNのデータのうちackされなかった分は送信バッファに残して接続後に送り直す
+    fn process_fast_open_reply(&self, socket: &mut Socket, packet: &TCPPacket) {
+        let syn_data = socket.send_param.next - socket.send_param.initial_seq - 1;
+        let acked = packet.get_ack() - socket.send_param.initial_seq - 1;
+        // タイムアウトしたSYNはデータを外して再送しているので，データがackされなくてもcookieは拒否されていない
+        let stripped = matches!(
+            socket.retransmission_queue.front(),
+            Some(item) if item.flag & tcpflags::SYN > 0 && item.len == 0
+        );
+        let cookie = packet
+            .get_option(tcpoptions::FAST_OPEN)
+            .and_then(tcpoptions::parse_fast_open)
+            .filter(|cookie| !cookie.is_empty());
+        let mut cookies = self.fast_open_cookies.lock().unwrap();
+        match cookie {
+            Some(cookie) => {
+                cookies.insert(socket.remote_addr, cookie.to_vec());
+            }
+            None if acked < syn_data && !stripped => {
+                // cookieを返さずにデータも受け取らないサーバーには，次からcookieを要求し直す
+                cookies.remove(&socket.remote_addr);
+            }
+            None => {}
+        }
+        dbg!("fast open reply", syn_data, acked);
+        socket.send_buffer.consume(acked as usize);
+        socket.send_param.next = packet.get_ack();
+        // SYNはackされたので，ackされなかったデータと一緒に再送キューから外す
+        socket.retransmission_queue.clear();
+        socket.fast_open_cookie = None;
+    }
+
+    /// ackされたセグメントを再送キューから除去する．一部だけackされたセグメントは残りの部分に縮める．
+    /// 再送していないセグメントがackされた場合はRTTと配送レートの計測値を返す
+    fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) -> RateSample {
+        let unacked_seq = socket.send_param.unacked_seq;
+        let mut sample = RateSample::default();
+        while let Some(item) = socket.retransmission_queue.front_mut() {
+            if unacked_seq < item.end() {
+                if item.seq < unacked_seq {
+                    // 一部だけackされてる
+                    socket.send_param.delivered += (unacked_seq - item.seq) as u64;
+                    socket.send_param.delivered_time = SystemTime::now();
+                    item.len -= unacked_seq - item.seq;
+                    item.seq = unacked_seq;
+                }
                 break;
             }
+            // ackされてるので除去
+            let item = socket.retransmission_queue.pop_front().unwrap();
+            dbg!("successfully acked", item.seq);
+            socket.send_param.delivered += item.len as u64;
+            socket.send_param.delivered_time = SystemTime::now();
+            if item.transmission_count == 1 {
+                // 再送したセグメントの計測値は曖昧なので使わない(Karnのアルゴリズム)
+                sample = rate_sample(&mut socket.send_param, &item);
+            }
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+            if item.flag & tcpflags::FIN > 0 && socket.status == TcpStatus::LastAck {
+                socket.status = TcpStatus::Closed;
+                dbg!("status: lastack ->", &socket.status);
+                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+            }
         }
+        sample
     }
 
-    /// ESTABLISHED状態のソケットに到着したパケットの処理
-    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("established handler");
+    /// 新しいデータをackするACKを受信した時の輻輳制御の処理．
+    /// 高速回復中であればRFC6582(NewReno)に従って部分ACKと完全ACKを処理する
+    fn on_new_ack(
+        &self,
+        socket: &mut Socket,
+        acked: u32,
+        sample: RateSample,
+        ece: bool,
+    ) -> Result<()> {
+        socket.send_param.dup_ack_count = 0;
+        let mss = socket.mss() as u32;
+        socket.congestion.on_ack(&AckSample {
+            acked,
+            flight_size: socket.send_param.flight_size(),
+            rtt: sample.rtt,
+            delivery_rate: sample.delivery_rate,
+            delivered: socket.send_param.delivered,
+            prior_delivered: sample.prior_delivered,
+            in_recovery: socket.send_param.in_fast_recovery,
+            ece,
+            mss,
+        });
+        let param = &mut socket.send_param;
+        if !param.in_fast_recovery {
+            return Ok(());
+        }
+        if param.unacked_seq >= param.recover {
+            // 完全ACK．膨らませたウィンドウをssthreshまで戻して高速回復を抜ける
+            param.inflation = 0;
+            param.in_fast_recovery = false;
+            dbg!("exit fast recovery", socket.congestion.cwnd());
+        } else {
+            // 部分ACK．次の欠落セグメントを再送し，ackされた分だけウィンドウを縮める
+            param.inflation = param.inflation.saturating_sub(acked);
+            if acked >= mss {
+                param.inflation += mss;
+            }
+            dbg!("partial ack", param.unacked_seq);
+            socket.retransmit_unacked_segment()?;
+        }
+        Ok(())
+    }
+
+    /// 重複ACKを受信した時の処理．3つ目の重複ACKで高速再送を行い高速回復に入る
+    fn on_duplicate_ack(&self, socket: &mut Socket) -> Result<()> {
+        let mss = socket.mss() as u32;
+        let param = &mut socket.send_param;
+        param.dup_ack_count = param.dup_ack_count.saturating_add(1);
+        dbg!("duplicate ack", param.unacked_seq, param.dup_ack_count);
+        if param.in_fast_recovery {
+            // 重複ACKの度にネットワークから1セグメント抜けたとみなしてウィンドウを膨らませる
+            param.inflation += mss;
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+            return Ok(());
+        }
+        // recoverを超えるまでは同じ損失に対して再度高速再送しない
+        if param.dup_ack_count == DUPLICATE_ACK_THRESHOLD && param.unacked_seq >= param.recover {
+            param.inflation = DUPLICATE_ACK_THRESHOLD as u32 * mss;
+            param.recover = param.next;
+            param.in_fast_recovery = true;
+            // ECEに応答して縮めたウィンドウ内の損失では再度縮めない
+            if param.unacked_seq >= param.cwr_seq {
+                socket
+                    .congestion
+                    .on_loss(socket.send_param.flight_size(), mss);
+            }
+            dbg!("fast retransmit", socket.send_param.unacked_seq);
+            socket.retransmit_unacked_segment()?;
+        }
+        Ok(())
+    }
+
+    /// ECEを受信した時の輻輳制御の処理(RFC3168 6.1.2)．損失と同じようにウィンドウを縮めるが，
+    /// 1ウィンドウに1回だけにし，次に送るデータセグメントにCWRを立てて相手に知らせる
+    fn on_ece(&self, socket: &mut Socket) {
+        let param = &mut socket.send_param;
+        if param.in_fast_recovery
+            || param.unacked_seq < param.recover
+            || param.unacked_seq < param.cwr_seq
+        {
+            // 既にこのウィンドウ内の輻輳に応答している
+            return;
+        }
+        param.cwr_seq = param.next;
+        param.cwr_pending = true;
+        socket
+            .congestion
+            .on_ecn(socket.send_param.flight_size(), socket.mss() as u32);
+        dbg!("ece received", socket.congestion.cwnd());
+    }
+
+    /// 相手が広告したウィンドウで送信ウィンドウを更新する．
+    /// 古いセグメントでウィンドウを巻き戻さないように，RFC793のSND.WL1とSND.WL2で判定する
+    fn update_send_window(&self, socket: &mut Socket, packet: &TCPPacket) {
+        let window = socket.remote_window(packet);
+        let param = &mut socket.send_param;
+        if packet.get_ack() < param.unacked_seq {
+            return;
+        }
+        if param.window_update_seq < packet.get_seq()
+            || (param.window_update_seq == packet.get_seq()
+                && param.window_update_ack <= packet.get_ack())
+        {
+            let opened = window > param.window;
+            param.window = window;
+            param.max_window = cmp::max(param.max_window, param.window);
+            param.window_update_seq = packet.get_seq();
+            param.window_update_ack = packet.get_ack();
+            if opened {
+                // ウィンドウが開くのを待っている送信処理を起こす
+                dbg!("send window opened", param.window);
+                param.persist_deadline = None;
+                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+            }
+        }
+    }
+
+    /// 到着したパケットのACKを処理し，ackされたデータを送信バッファから捨てて続きを送信する．
+    /// 未送信セグメントに対するACKやACKフラグのないパケットなど，破棄すべき場合はfalseを返す
+    fn process_ack(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<bool> {
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            // ACKが立っていないパケットは破棄
+            return Ok(false);
+        }
+        // RFC5961 5.2のACKの検証．ackはSND.UNA - MAX.SND.WND以上SND.NXT以下でなければならない
+        let oldest_ack = socket
+            .send_param
+            .unacked_seq
+            .saturating_sub(socket.send_param.max_window);
+        if packet.get_ack() < oldest_ack || socket.send_param.next < packet.get_ack() {
+            // 未送信のデータや古すぎるデータへのackは，ブラインドで注入されたセグメントかもしれない
+            dbg!("unacceptable ack", packet.get_ack());
+            self.send_challenge_ack(socket)?;
+            return Ok(false);
+        }
         if socket.send_param.unacked_seq < packet.get_ack()
             && packet.get_ack() <= socket.send_param.next
         {
+            let acked = packet.get_ack() - socket.send_param.unacked_seq;
             socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            // 未送信セグメントに対するackは破棄
-            return Ok(());
+            socket.send_buffer.consume(acked as usize);
+            if matches!(socket.send_param.urgent_pointer, Some(up) if up <= packet.get_ack()) {
+                // 緊急データがackされたので緊急モードを抜ける
+                socket.send_param.urgent_pointer = None;
+            }
+            let sample = self.delete_acked_segment_from_retransmission_queue(socket);
+            let ece = socket.ecn && packet.get_flag() & tcpflags::ECE > 0;
+            self.on_new_ack(socket, acked, sample, ece)?;
+        } else if socket.send_param.unacked_seq == packet.get_ack()
+            && is_duplicate_ack(socket, packet)
+        {
+            self.on_duplicate_ack(socket)?;
         }
-        if packet.get_flag() & tcpflags::ACK == 0 {
-            // ACKが立っていないパケットは破棄
+        if socket.ecn && packet.get_flag() & tcpflags::ECE > 0 {
+            self.on_ece(socket);
+        }
+        self.update_send_window(socket, packet);
+        self.transmit(socket, false)?;
+        if is_window_probe(socket, packet) {
+            // 現在の受信ウィンドウを知らせる
+            socket.send_ack()?;
+        }
+        Ok(true)
+    }
+
+    /// ESTABLISHED状態のソケットに到着したパケットの処理
+    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket, ce: bool) -> Result<()> {
+        dbg!("established handler");
+        if !self.process_ack(socket, packet)? {
             return Ok(());
         }
+        refresh_keepalive(socket, packet);
+        process_options_and_ecn(socket, packet, ce);
+        if packet.get_flag() & tcpflags::URG > 0 {
+            self.process_urgent(socket, packet);
+        }
         if !packet.payload().is_empty() {
             self.process_payload(socket, &packet)?;
         }
@@ -548,75 +1703,164 @@ impl TCP {
         Ok(())
     }
 
+    /// 緊急ポインタを受け取る．緊急データ(seq + 緊急ポインタ - 1のバイト)は，
+    /// oob_inlineでなければ受信した時に通常のデータから取り出してrecv_urgentで読めるようにする
+    fn process_urgent(&self, socket: &mut Socket, packet: &TCPPacket) {
+        let pointer = packet.get_urgent_pointer() as u32;
+        if pointer == 0 || socket.oob_inline {
+            return;
+        }
+        let urgent_seq = packet.get_seq() + pointer - 1;
+        if urgent_seq < socket.recv_param.next
+            || matches!(socket.recv_param.urgent_seq, Some(seq) if seq >= urgent_seq)
+        {
+            // 受信済みの緊急データ
+            return;
+        }
+        dbg!("urgent pointer received", urgent_seq);
+        socket.recv_param.urgent_seq = Some(urgent_seq);
+    }
+
     /// パケットのペイロードを受信バッファにコピーする
     fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        // バッファにおける読み込みのヘッド位置．
-        let offset = socket.recv_buffer.len() - socket.recv_param.window as usize
-            + (packet.get_seq() - socket.recv_param.next) as usize;
-        let copy_size = cmp::min(packet.payload().len(), socket.recv_buffer.len() - offset);
-        socket.recv_buffer[offset..offset + copy_size]
-            .copy_from_slice(&packet.payload()[..copy_size]);
-        socket.recv_param.tail =
-            cmp::max(socket.recv_param.tail, packet.get_seq() + copy_size as u32); // ロス再送の際穴埋めされるためにmaxをとる
-
-        if packet.get_seq() == socket.recv_param.next {
+        let mut seq = packet.get_seq();
+        let mut payload = packet.payload();
+        // 受信側はPSHで動作を変えず，送信側が書き込みの区切りに立てたPSHをトレースに出すだけ
+        let push = packet.get_flag() & tcpflags::PSH > 0;
+        dbg!("data segment", seq, payload.len(), push);
+        if seq + payload.len() as u32 <= socket.recv_param.next {
+            // 全て受信済みのセグメントの再送．ACKが失われた可能性があるのですぐにACKを返す
+            dbg!("duplicate segment", seq);
+            socket.send_ack()?;
+            return Ok(());
+        }
+        if seq < socket.recv_param.next {
+            // 一部だけ受信済みのセグメント．まだ受信していない部分だけを使う
+            dbg!("partially duplicate segment", seq);
+            payload = &payload[(socket.recv_param.next - seq) as usize..];
+            seq = socket.recv_param.next;
+        }
+        // 受信バッファの末尾はrecv_param.nextに対応するので，そこからの距離に書き込む
+        let offset = (seq - socket.recv_param.next) as usize;
+        let copy_size = socket.recv_buffer.write_at(offset, payload);
+        socket.recv_param.tail = cmp::max(socket.recv_param.tail, seq + copy_size as u32); // ロス再送の際穴埋めされるためにmaxをとる
+
+        // 順序が入れ替わったセグメントか，欠落を埋めるセグメントを受信した
+        let reordered =
+            seq != socket.recv_param.next || socket.recv_param.tail > seq + copy_size as u32;
+        if seq == socket.recv_param.next {
             // 順序入れ替わり無しの場合のみrecv_param.nextを進められる
+            let received = socket.recv_param.tail - seq;
+            socket.recv_buffer.commit(received as usize);
             socket.recv_param.next = socket.recv_param.tail;
-            socket.recv_param.window -= (socket.recv_param.tail - packet.get_seq()) as u16;
+            socket.recv_param.window -= received;
+            socket.measure_recv_rtt();
+            if socket.read_shutdown {
+                discard_received_data(socket);
+            }
+        }
+        if let Some(urgent_seq) = socket.recv_param.urgent_seq {
+            if seq <= urgent_seq && urgent_seq < seq + copy_size as u32 {
+                // 緊急データのバイトが届いた
+                socket.urgent_data = Some(payload[(urgent_seq - seq) as usize]);
+                dbg!("urgent data", socket.urgent_data);
+            }
         }
         if copy_size > 0 {
             // 受信バッファにコピーが成功
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &[],
-            )?;
+            if reordered {
+                // 送信側が高速再送できるようにすぐにACKを返し，しばらくquick ACKモードにする
+                socket.recv_param.quick_acks = QUICK_ACK_SEGMENTS;
+                socket.send_ack()?;
+            } else {
+                self.delay_ack(socket)?;
+            }
         } else {
             // 受信バッファが溢れた時はセグメントを破棄
             dbg!("recv buffer overflow");
         }
+        // 遅延ACKの有無に関わらず，データが届けばすぐにrecvを起こす．
+        // PSHの付いたセグメントも付いていないセグメントも待たせずに読ませる
         self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
         Ok(())
     }
 
-    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+    /// RFC1122の遅延ACK．2セグメント受信する毎か，DELAYED_ACK_TIMEOUT経過後にACKを返す．
+    /// quick ACKモードの間は遅延させない．送信データがあればACKはそれに載せる
+    fn delay_ack(&self, socket: &mut Socket) -> Result<()> {
+        let param = &mut socket.recv_param;
+        param.unacked_segments += 1;
+        if param.quick_acks > 0 {
+            param.quick_acks -= 1;
+            return socket.send_ack();
+        }
+        if param.unacked_segments >= 2 {
+            return socket.send_ack();
+        }
+        if param.ack_deadline.is_none() {
+            param.ack_deadline =
+                Some(SystemTime::now() + Duration::from_millis(DELAYED_ACK_TIMEOUT));
+        }
+        Ok(())
+    }
+
+    /// CLOSEWAIT or LASTACK状態のソケットに到着したパケットの処理
+    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket, ce: bool) -> Result<()> {
         dbg!("closewait | lastack handler");
-        socket.send_param.unacked_seq = packet.get_ack();
+        if self.process_ack(socket, packet)? {
+            refresh_keepalive(socket, packet);
+            process_options_and_ecn(socket, packet, ce);
+        }
         Ok(())
     }
 
     /// FINWAIT1 or FINWAIT2状態のソケットに到着したパケットの処理
-    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket, ce: bool) -> Result<()> {
         dbg!("finwait handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            // 未送信セグメントに対するackは破棄
+        if !self.process_ack(socket, packet)? {
             return Ok(());
         }
-        if packet.get_flag() & tcpflags::ACK == 0 {
-            // ACKが立っていないパケットは破棄
-            return Ok(());
+        process_options_and_ecn(socket, packet, ce);
+        if packet.get_flag() & tcpflags::URG > 0 {
+            self.process_urgent(socket, packet);
         }
         if !packet.payload().is_empty() {
             self.process_payload(socket, &packet)?;
         }
 
-        if socket.status == TcpStatus::FinWait1
-            && socket.send_param.next == socket.send_param.unacked_seq
-        {
-            // 送信したFINがackされていればFinWait2へ遷移
-            socket.status = TcpStatus::FinWait2;
-            dbg!("status: finwait1 ->", &socket.status);
+        if socket.send_param.next == socket.send_param.unacked_seq {
+            // 送信したFINがackされていればFinWait2かTimeWaitへ遷移
+            let next_status = match socket.status {
+                TcpStatus::FinWait1 => Some(TcpStatus::FinWait2),
+                TcpStatus::Closing => Some(TcpStatus::TimeWait),
+                _ => None,
+            };
+            if let Some(status) = next_status {
+                dbg!("status:", &socket.status, "->", &status);
+                socket.status = status;
+                if socket.status == TcpStatus::TimeWait {
+                    socket.close_deadline = Some(time_wait_deadline());
+                    self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+                }
+            }
         }
 
         if packet.get_flag() & tcpflags::FIN > 0 {
-            // 本来はCLOSING stateも考慮する必要があるが省略
-            socket.recv_param.next += 1;
+            // 再送されたFINにはACKだけ返す
+            let next_status = match socket.status {
+                TcpStatus::FinWait1 => Some(TcpStatus::Closing),
+                TcpStatus::FinWait2 => Some(TcpStatus::TimeWait),
+                _ => None,
+            };
+            if let Some(status) = next_status {
+                socket.recv_param.next += 1;
+                dbg!("status:", &socket.status, "->", &status);
+                socket.status = status;
+            }
+            if socket.status == TcpStatus::TimeWait {
+                // TIME_WAITに入った時と，FINが再送されてきた時に2MSLを測り直す(RFC793)
+                socket.close_deadline = Some(time_wait_deadline());
+            }
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
@@ -624,6 +1868,8 @@ impl TCP {
                 &[],
             )?;
             self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+            // 相手のFINを待っているrecvを起こす
+            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
         }
         Ok(())
     }
@@ -637,6 +1883,235 @@ impl TCP {
     }
 }
 
+/// 接続が確立した後の状態(RFC793のsynchronized state)かどうか
+fn is_synchronized(status: &TcpStatus) -> bool {
+    !matches!(
+        status,
+        TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcvd | TcpStatus::Closed
+    )
+}
+
+/// 今TIME_WAITに入ったソケットがTIME_WAITを抜ける時刻
+fn time_wait_deadline() -> SystemTime {
+    SystemTime::now() + Duration::from_secs(2 * MAX_SEGMENT_LIFETIME)
+}
+
+/// seqが受信ウィンドウ内にあるかを判定する．ウィンドウが0ならnextと一致する時だけ
+fn is_in_recv_window(socket: &Socket, seq: u32) -> bool {
+    let next = socket.recv_param.next;
+    if socket.recv_param.window == 0 {
+        seq == next
+    } else {
+        next <= seq && seq < next + socket.recv_param.window
+    }
+}
+
+/// 受け付けたセグメントが受信ウィンドウ内にあれば，相手が生きているとしてキープアライブをやり直す．
+/// ウィンドウ外のセグメントは偽造されたものかもしれないので，切れた接続を延命させない
+fn refresh_keepalive(socket: &mut Socket, packet: &TCPPacket) {
+    let seq = packet.get_seq();
+    let len = packet.payload().len() as u32;
+    if is_in_recv_window(socket, seq) || (len > 0 && is_in_recv_window(socket, seq + len - 1)) {
+        socket.last_received = SystemTime::now();
+        socket.keepalive_probes = 0;
+    }
+}
+
+/// recvで読み込めるバイト数を返す．帯域外で受け取る緊急データのバイトは受信バッファから読み飛ばし，
+/// その手前までしか読ませない
+fn readable_size(socket: &mut Socket) -> usize {
+    if let Some(mark) = socket.recv_param.urgent_seq {
+        let head = socket.recv_param.next - socket.recv_buffer.len() as u32;
+        if mark == head && mark < socket.recv_param.next {
+            socket.recv_buffer.consume(1);
+            socket.recv_param.window += 1;
+            socket.recv_param.urgent_seq = None;
+        } else if head < mark && mark < socket.recv_param.next {
+            return (mark - head) as usize;
+        }
+    }
+    socket.recv_buffer.len()
+}
+
+/// 受信バッファのデータを捨てて受信ウィンドウを開く．受信側をshutdownした後に使う
+fn discard_received_data(socket: &mut Socket) {
+    let received = socket.recv_buffer.len();
+    socket.recv_buffer.consume(received);
+    socket.recv_param.window += received as u32;
+}
+
+/// MD5署名の鍵として使える長さか確認する
+fn check_md5_key(key: Option<&[u8]>) -> Result<()> {
+    if let Some(key) = key {
+        if key.is_empty() || key.len() > tcpoptions::MD5_MAX_KEY_LEN {
+            anyhow::bail!("invalid md5 key length: {}", key.len());
+        }
+    }
+    Ok(())
+}
+
+/// 1秒間に送れるチャレンジACKの数．残りの数から他の接続のチャレンジACKを推測されないように，
+/// 毎秒ランダムに決める
+fn challenge_ack_limit() -> u32 {
+    CHALLENGE_ACK_LIMIT / 2 + rand::thread_rng().gen_range(0..CHALLENGE_ACK_LIMIT)
+}
+
+/// 受け付けたセグメントのオプションとECNの情報を処理する．ceはIPヘッダにCEマークが付いていたか．
+/// RSTや不正なACKで接続の設定を書き換えられないように，セグメントを検証した後に呼ぶ
+fn process_options_and_ecn(socket: &mut Socket, packet: &TCPPacket, ce: bool) {
+    socket.process_options(packet);
+    if let Err(error) = socket.process_ecn(packet.get_flag(), ce) {
+        dbg!(error);
+    }
+}
+
+/// 半開きの接続として数えられているソケットがSYNRCVDを抜けたか破棄される時に，
+/// 生成元のリスニングソケットのhalf_openから除く
+fn release_half_open(table: &mut HashMap<SockID, Socket>, sock_id: SockID) {
+    let listening_socket = match table.get_mut(&sock_id) {
+        Some(socket) if socket.counted_half_open => {
+            socket.counted_half_open = false;
+            socket.listening_socket
+        }
+        _ => return,
+    };
+    if let Some(ls) = listening_socket.and_then(|id| table.get_mut(&id)) {
+        ls.half_open = ls.half_open.saturating_sub(1);
+    }
+}
+
+/// リスニングソケットのacceptされるのを待つ接続済みソケットがbacklogに達しているか
+fn accept_queue_full(listening_socket: &Socket) -> bool {
+    listening_socket.connected_connection_queue.len() >= listening_socket.backlog
+}
+
+/// 受信ウィンドウと輻輳ウィンドウを考慮して，今送信できるバイト数を返す
+fn usable_window(socket: &Socket) -> usize {
+    let cwnd = socket.congestion.cwnd() + socket.send_param.inflation;
+    let window = cmp::min(socket.send_param.window, cwnd);
+    window.saturating_sub(socket.send_param.flight_size()) as usize
+}
+
+/// 送信側のSWS回避(RFC1122 4.2.3.4)．MSS分か残りの全てを送れる時，
+/// または相手の最大ウィンドウの半分以上を送れる時だけ送信する．送信してよいバイト数を返す
+fn sendable_size(socket: &Socket, remaining: usize) -> usize {
+    let mss = socket.mss();
+    let size = cmp::min(mss, cmp::min(usable_window(socket), remaining));
+    if size == mss || size == remaining || size >= socket.send_param.max_window as usize / 2 {
+        size
+    } else {
+        0
+    }
+}
+
+/// ペーシングレートに従って，sizeバイト送信した後に次の送信まで空ける時間を返す．
+/// ペーシングしない場合はNone
+fn pacing_interval(socket: &Socket, size: usize) -> Option<Duration> {
+    match socket.congestion.pacing_rate() {
+        Some(rate) if rate > 0 => Some(Duration::from_secs_f64(size as f64 / rate as f64)),
+        _ => None,
+    }
+}
+
+/// ackされたセグメントの送信時点の記録からRTTと配送レートを計算する
+fn rate_sample(param: &mut SendParam, item: &RetransmissionQueueEntry) -> RateSample {
+    let rtt = item.latest_transmission_time.elapsed().ok();
+    // 送信にかかった時間とackにかかった時間の長い方を計測区間とする
+    let send_elapsed = item
+        .latest_transmission_time
+        .duration_since(item.first_sent_time)
+        .unwrap_or_default();
+    let ack_elapsed = param
+        .delivered_time
+        .duration_since(item.delivered_time)
+        .unwrap_or_default();
+    let interval = cmp::max(send_elapsed, ack_elapsed);
+    let delivery_rate = if interval.as_micros() > 0 {
+        Some(((param.delivered - item.delivered) as u128 * 1_000_000 / interval.as_micros()) as u64)
+    } else {
+        None
+    };
+    param.first_sent_time = item.latest_transmission_time;
+    RateSample {
+        rtt,
+        delivery_rate,
+        prior_delivered: item.delivered,
+    }
+}
+
+/// RFC5681の重複ACKの条件を満たすかを判定する
+fn is_duplicate_ack(socket: &Socket, packet: &TCPPacket) -> bool {
+    packet.get_flag() & tcpflags::ACK > 0
+        && packet.get_flag() & (tcpflags::SYN | tcpflags::FIN) == 0
+        && packet.payload().is_empty()
+        && socket.remote_window(packet) == socket.send_param.window
+        && socket.send_param.flight_size() > 0
+}
+
+/// ウィンドウプローブのように，受信済みのseqを持つデータのないセグメントかを判定する
+fn is_window_probe(socket: &Socket, packet: &TCPPacket) -> bool {
+    packet.payload().is_empty()
+        && packet.get_flag() & (tcpflags::SYN | tcpflags::FIN) == 0
+        && packet.get_seq() < socket.recv_param.next
+}
+
+/// 相手の受信ウィンドウが0で送信できない時にパーシストタイマーを起動する．
+/// 送信中のセグメントがあればそのACKでウィンドウが分かるので起動しない
+fn start_persist_timer(socket: &mut Socket) {
+    let param = &mut socket.send_param;
+    if param.window == 0 && param.flight_size() == 0 && param.persist_deadline.is_none() {
+        dbg!("start persist timer");
+        param.persist_backoff = 0;
+        param.persist_deadline = Some(SystemTime::now() + persist_timeout(0));
+    }
+}
+
+/// パーシストタイマーの期限が来ていればウィンドウプローブを送り，次の期限を延ばす．
+/// ウィンドウが開いていればタイマーを止める
+fn probe_zero_window(socket: &mut Socket) -> Result<()> {
+    let deadline = match socket.send_param.persist_deadline {
+        Some(deadline) => deadline,
+        None => return Ok(()),
+    };
+    if socket.send_param.window > 0 {
+        socket.send_param.persist_deadline = None;
+    } else if deadline <= SystemTime::now() {
+        dbg!("window probe", socket.send_param.persist_backoff);
+        socket.send_probe()?;
+        let param = &mut socket.send_param;
+        param.persist_backoff = param.persist_backoff.saturating_add(1);
+        param.persist_deadline = Some(SystemTime::now() + persist_timeout(param.persist_backoff));
+    }
+    Ok(())
+}
+
+/// ウィンドウプローブの間隔．プローブする度に倍にする
+fn persist_timeout(backoff: u8) -> Duration {
+    let timeout = RETRANSMITTION_TIMEOUT.saturating_mul(1 << cmp::min(backoff, 16));
+    Duration::from_secs(cmp::min(timeout, MAX_PERSIST_TIMEOUT))
+}
+
+/// 再送タイムアウト時の輻輳制御の処理
+fn on_retransmission_timeout(socket: &mut Socket, new_loss: bool) {
+    let mss = socket.mss() as u32;
+    if new_loss {
+        socket
+            .congestion
+            .on_rto(socket.send_param.flight_size(), mss);
+        socket.send_param.recover = socket.send_param.next;
+    } else {
+        // 既に数えた損失なので，ssthreshはそのままで輻輳ウィンドウだけを1MSSに戻す
+        let ssthresh = socket.congestion.ssthresh();
+        socket.congestion.set_window(mss, ssthresh);
+    }
+    // 高速回復中でもタイムアウトしたら抜ける(RFC6582 3.2)
+    let param = &mut socket.send_param;
+    param.dup_ack_count = 0;
+    param.in_fast_recovery = false;
+    param.inflation = 0;
+    dbg!("retransmission timeout", socket.congestion.ssthresh());
+}
+
 /// 宛先IPアドレスに対する送信元インタフェースのIPアドレスを取得する
 /// iproute2-ss180129で動作を確認．バージョンによって挙動が変わるかも
 fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
@@ -656,3 +2131,1141 @@ fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
     dbg!("source addr", ip);
     ip.parse().context("failed to parse source ip")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ring_buffer::RingBuffer;
+    use crate::socket::{ECN_ECT0, MSS};
+    use std::time::Instant;
+
+    const SENDER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const RECEIVER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+    /// ESTABLISHEDで向かい合う送信側と受信側のソケットを登録し，そのIDを返す．
+    /// 3ウェイハンドシェイクは省略してシーケンス番号だけを合わせる．
+    /// 送信用のrawソケットを開くので，テストにはroot権限が必要
+    fn connect_pair(tcp: &TCP, buffer_size: usize) -> (SockID, SockID) {
+        let mut sender = Socket::new(
+            SENDER_ADDR,
+            RECEIVER_ADDR,
+            40000,
+            50000,
+            TcpStatus::Established,
+        )
+        .unwrap();
+        let mut receiver = Socket::new(
+            RECEIVER_ADDR,
+            SENDER_ADDR,
+            50000,
+            40000,
+            TcpStatus::Established,
+        )
+        .unwrap();
+        sender.send_param.initial_seq = 1000;
+        sender.send_param.unacked_seq = 1001;
+        sender.send_param.next = 1001;
+        sender.send_param.window = buffer_size as u32;
+        sender.recv_param.initial_seq = 5000;
+        sender.recv_param.next = 5001;
+        sender.recv_param.tail = 5001;
+        receiver.send_param.initial_seq = 5000;
+        receiver.send_param.unacked_seq = 5001;
+        receiver.send_param.next = 5001;
+        receiver.recv_param.initial_seq = 1000;
+        receiver.recv_param.next = 1001;
+        receiver.recv_param.tail = 1001;
+        receiver.recv_buffer = RingBuffer::new(buffer_size);
+        receiver.recv_param.window = buffer_size as u32;
+        let ids = (sender.get_sock_id(), receiver.get_sock_id());
+        let mut table = tcp.sockets.write().unwrap();
+        table.insert(ids.0, sender);
+        table.insert(ids.1, receiver);
+        ids
+    }
+
+    /// タイマースレッドを起動する
+    fn start_timer(tcp: &Arc<TCP>) {
+        let cloned_tcp = tcp.clone();
+        thread::spawn(move || cloned_tcp.timer());
+    }
+
+    /// fromのソケットが送信したセグメントを，lostがtrueを返すものを除いてtoのソケットに届ける
+    fn deliver(
+        tcp: &TCP,
+        table: &mut HashMap<SockID, Socket>,
+        from: SockID,
+        to: SockID,
+        lost: &mut dyn FnMut(&TCPPacket) -> bool,
+    ) {
+        let packets: Vec<TCPPacket> = table.get_mut(&from).unwrap().sent.drain(..).collect();
+        let socket = table.get_mut(&to).unwrap();
+        for packet in packets.iter().filter(|packet| !lost(packet)) {
+            tcp.established_handler(socket, packet, false).unwrap();
+        }
+    }
+
+    /// 受信バッファにあるデータを全て読み出してreceivedに追加する．データがなければブロックしない
+    fn read_available(tcp: &TCP, sock_id: SockID, received: &mut Vec<u8>) {
+        loop {
+            let table = tcp.sockets.read().unwrap();
+            let socket = &table[&sock_id];
+            let readable = socket.recv_buffer.len();
+            drop(table);
+            if readable == 0 {
+                return;
+            }
+            let mut buffer = vec![0; readable];
+            let size = tcp.recv(sock_id, &mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..size]);
+        }
+    }
+
+    #[test]
+    fn test_fast_retransmit_on_loss() {
+        let tcp = Arc::new(TCP::without_threads());
+        let (sender, receiver) = connect_pair(&tcp, 65535);
+        start_timer(&tcp);
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let cloned_tcp = tcp.clone();
+        let cloned_data = data.clone();
+        let handle = thread::spawn(move || cloned_tcp.send(sender, &cloned_data).unwrap());
+
+        // 10番目に送信されたデータセグメントを1度だけ落とす
+        let mut segments = 0;
+        let mut lost_seq = None;
+        let mut fast_retransmits = 0;
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while received.len() < data.len() {
+            assert!(Instant::now() < deadline, "transfer stalled");
+            let mut table = tcp.sockets.write().unwrap();
+            deliver(&tcp, &mut table, sender, receiver, &mut |packet| {
+                if packet.payload().is_empty() {
+                    return false;
+                }
+                segments += 1;
+                if segments == 10 {
+                    lost_seq = Some(packet.get_seq());
+                    return true;
+                }
+                false
+            });
+            deliver(&tcp, &mut table, receiver, sender, &mut |_| false);
+            // ロックを持っている間はタイマーは再送できないので，ここで見つかる再送はACKを契機にした高速再送
+            fast_retransmits += table[&sender]
+                .sent
+                .iter()
+                .filter(|packet| Some(packet.get_seq()) == lost_seq)
+                .count();
+            drop(table);
+            read_available(&tcp, receiver, &mut received);
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.join().unwrap();
+        assert!(received == data);
+        assert!(lost_seq.is_some());
+        // 失われたセグメントは再送タイムアウトを待たずに1度だけ再送されている
+        assert_eq!(fast_retransmits, 1);
+    }
+
+    #[test]
+    fn test_zero_window_probe_and_resume() {
+        let tcp = Arc::new(TCP::without_threads());
+        let (sender, receiver) = connect_pair(&tcp, 4380);
+        start_timer(&tcp);
+        let data: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        let cloned_tcp = tcp.clone();
+        let cloned_data = data.clone();
+        let handle = thread::spawn(move || cloned_tcp.send(sender, &cloned_data).unwrap());
+
+        // 受信側が読まないので受信バッファが埋まり，ウィンドウが0になる．
+        // 送信側がパーシストタイマーでウィンドウプローブを送り，受信側がそれに応答するまで読まずに待つ
+        let mut probes = 0;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while probes == 0 {
+            assert!(Instant::now() < deadline, "no window probe");
+            let mut table = tcp.sockets.write().unwrap();
+            let zero_window = table[&receiver].recv_param.window == 0;
+            deliver(&tcp, &mut table, sender, receiver, &mut |_| {
+                if zero_window {
+                    probes += 1;
+                }
+                false
+            });
+            deliver(&tcp, &mut table, receiver, sender, &mut |_| false);
+            drop(table);
+            thread::sleep(Duration::from_millis(1));
+        }
+        // プローブへの応答でもウィンドウは0のまま
+        let table = tcp.sockets.read().unwrap();
+        assert_eq!(table[&sender].send_param.window, 0);
+        drop(table);
+
+        // 読み始めるとウィンドウ更新で送信が再開する
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            assert!(Instant::now() < deadline, "transfer stalled");
+            let mut table = tcp.sockets.write().unwrap();
+            deliver(&tcp, &mut table, sender, receiver, &mut |_| false);
+            deliver(&tcp, &mut table, receiver, sender, &mut |_| false);
+            drop(table);
+            read_available(&tcp, receiver, &mut received);
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.join().unwrap();
+        assert!(received == data);
+    }
+
+    /// ESTABLISHEDのソケットを生成する．送信用のrawソケットを開くので，テストにはroot権限が必要
+    fn established_socket() -> Socket {
+        let mut socket = Socket::new(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 1, 1),
+            40000,
+            50000,
+            TcpStatus::Established,
+        )
+        .unwrap();
+        socket.send_param.initial_seq = 1000;
+        socket.send_param.unacked_seq = 1001;
+        socket.send_param.next = 1001;
+        socket.send_param.recover = 1001;
+        socket.send_param.cwr_seq = 1001;
+        socket.recv_param.initial_seq = 5000;
+        socket.recv_param.next = 5001;
+        socket.remote_mss = MSS as u16;
+        socket
+    }
+
+    /// MSSのデータセグメントをcount個送信する
+    fn send_segments(socket: &mut Socket, count: usize) {
+        for _ in 0..count {
+            let payload = [0; MSS];
+            socket.send_buffer.push(&payload);
+            socket
+                .send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &payload,
+                )
+                .unwrap();
+            socket.send_param.next += MSS as u32;
+        }
+    }
+
+    /// 相手からackまでをackするACKが届いた
+    fn receive_ack(tcp: &TCP, socket: &mut Socket, ack: u32) {
+        let mut packet = TCPPacket::new(&[], 0);
+        packet.set_seq(socket.recv_param.next);
+        packet.set_ack(ack);
+        packet.set_flag(tcpflags::ACK);
+        packet.set_window_size((socket.send_param.window >> socket.send_param.window_shift) as u16);
+        assert!(tcp.process_ack(socket, &packet).unwrap());
+    }
+
+    /// 相手からseqで始まるデータセグメントが届いた
+    fn receive_data(tcp: &TCP, socket: &mut Socket, seq: u32, payload: &[u8]) {
+        let mut packet = TCPPacket::new(&[], payload.len());
+        packet.set_seq(seq);
+        packet.set_ack(socket.send_param.next);
+        packet.set_flag(tcpflags::ACK);
+        packet.set_window_size((socket.send_param.window >> socket.send_param.window_shift) as u16);
+        packet.set_payload(payload);
+        tcp.process_payload(socket, &packet).unwrap();
+    }
+
+    #[test]
+    fn test_window_scale() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.status = TcpStatus::SynSent;
+        let mut syn_ack = TCPPacket::new(&tcpoptions::window_scale(5), 0);
+        syn_ack.set_flag(tcpflags::SYN | tcpflags::ACK);
+        syn_ack.set_window_size(1000);
+        socket.process_options(&syn_ack);
+        assert_eq!(socket.send_param.window_shift, 5);
+        assert!(socket.recv_param.window_shift > 0);
+        // SYNのウィンドウはシフトしない
+        assert_eq!(socket.remote_window(&syn_ack), 1000);
+
+        socket.status = TcpStatus::Established;
+        let mut packet = TCPPacket::new(&[], 0);
+        packet.set_seq(socket.recv_param.next);
+        packet.set_ack(socket.send_param.next);
+        packet.set_flag(tcpflags::ACK);
+        packet.set_window_size(1000);
+        assert!(tcp.process_ack(&mut socket, &packet).unwrap());
+        assert_eq!(socket.send_param.window, 1000 << 5);
+
+        // 接続後のSYNでは交渉し直さない
+        let mut syn = TCPPacket::new(&[], 0);
+        syn.set_flag(tcpflags::SYN);
+        socket.process_options(&syn);
+        assert_eq!(socket.send_param.window_shift, 5);
+        // 相手のSYNにオプションがなければどちらもシフトしない
+        socket.status = TcpStatus::SynRcvd;
+        socket.process_options(&syn);
+        assert_eq!(socket.send_param.window_shift, 0);
+        assert_eq!(socket.recv_param.window_shift, 0);
+    }
+
+    #[test]
+    fn test_remote_mss() {
+        let mut socket = established_socket();
+        socket.status = TcpStatus::SynSent;
+        let mut syn_ack = TCPPacket::new(&tcpoptions::mss(1000), 0);
+        syn_ack.set_flag(tcpflags::SYN | tcpflags::ACK);
+        socket.process_options(&syn_ack);
+        assert_eq!(socket.mss(), 1000);
+        // MSSオプションはSYNでだけ受け付ける
+        let mut ack = TCPPacket::new(&tcpoptions::mss(500), 0);
+        ack.set_flag(tcpflags::ACK);
+        socket.process_options(&ack);
+        assert_eq!(socket.mss(), 1000);
+        // 自分のMSSより大きくも，小さすぎるようにもしない
+        socket.remote_mss = 9000;
+        assert_eq!(socket.mss(), MSS);
+        socket.remote_mss = 1;
+        assert_eq!(socket.mss(), 88);
+
+        // 送信するセグメントは相手のMSSで分割する
+        let tcp = TCP::without_threads();
+        socket.status = TcpStatus::Established;
+        socket.remote_mss = 1000;
+        socket.nodelay = true;
+        socket.send_buffer.push(&[0; 2500]);
+        tcp.transmit(&mut socket, false).unwrap();
+        let lens: Vec<u32> = socket
+            .retransmission_queue
+            .iter()
+            .map(|item| item.len)
+            .collect();
+        assert_eq!(lens, vec![1000, 1000, 500]);
+    }
+
+    #[test]
+    fn test_half_open_count() {
+        let tcp = TCP::without_threads();
+        let server = Ipv4Addr::new(10, 0, 1, 1);
+        let client = Ipv4Addr::new(10, 0, 0, 1);
+        let listener = tcp.listen(server, 50000, 1).unwrap();
+        let syn = |port| {
+            let mut packet = TCPPacket::new(&[], 0);
+            packet.set_src(port);
+            packet.set_dest(50000);
+            packet.set_seq(5000);
+            packet.set_flag(tcpflags::SYN);
+            packet.set_window_size(4380);
+            packet
+        };
+        tcp.listen_handler(tcp.sockets.write().unwrap(), listener, &syn(40000), client)
+            .unwrap();
+        assert_eq!(tcp.sockets.read().unwrap()[&listener].half_open, 1);
+        // backlogに達していれば次のSYNは捨てる
+        tcp.listen_handler(tcp.sockets.write().unwrap(), listener, &syn(40001), client)
+            .unwrap();
+        assert_eq!(tcp.sockets.read().unwrap().len(), 2);
+
+        // 最後のACKで確立すれば半開きの接続ではなくなる
+        let sock_id = SockID(server, client, 50000, 40000);
+        let mut ack = TCPPacket::new(&[], 0);
+        ack.set_seq(5001);
+        ack.set_ack(tcp.sockets.read().unwrap()[&sock_id].send_param.next);
+        ack.set_flag(tcpflags::ACK);
+        tcp.synrcvd_handler(tcp.sockets.write().unwrap(), sock_id, &ack, false)
+            .unwrap();
+        let table = tcp.sockets.read().unwrap();
+        assert_eq!(table[&sock_id].status, TcpStatus::Established);
+        assert_eq!(table[&listener].half_open, 0);
+    }
+
+    #[test]
+    fn test_duplicate_syn_keeps_transmission_count() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.status = TcpStatus::SynRcvd;
+        socket.send_param.unacked_seq = socket.send_param.initial_seq;
+        socket
+            .send_tcp_packet(
+                socket.send_param.initial_seq,
+                socket.recv_param.next,
+                tcpflags::SYN | tcpflags::ACK,
+                &[],
+            )
+            .unwrap();
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        let mut syn = TCPPacket::new(&[], 0);
+        syn.set_seq(5000);
+        syn.set_flag(tcpflags::SYN);
+        tcp.synrcvd_handler(tcp.sockets.write().unwrap(), sock_id, &syn, false)
+            .unwrap();
+        let table = tcp.sockets.read().unwrap();
+        let socket = &table[&sock_id];
+        // SYN/ACKは送り直すが，半開きの接続の期限は延ばさない
+        assert_eq!(socket.sent.len(), 2);
+        assert_eq!(socket.sent[1].get_flag(), tcpflags::SYN | tcpflags::ACK);
+        assert_eq!(socket.retransmission_queue[0].transmission_count, 1);
+    }
+
+    #[test]
+    fn test_connect_congestion_control() {
+        let tcp = TCP::without_threads();
+        assert!(tcp
+            .set_connect_congestion_control(RECEIVER_ADDR, Some("unknown"))
+            .is_err());
+        tcp.set_connect_congestion_control(RECEIVER_ADDR, Some("dctcp"))
+            .unwrap();
+        assert_eq!(
+            tcp.connect_congestion.lock().unwrap()[&RECEIVER_ADDR],
+            "dctcp"
+        );
+        tcp.set_connect_congestion_control(RECEIVER_ADDR, None)
+            .unwrap();
+        assert!(tcp.connect_congestion.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_options_after_validation() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        let timeout = Duration::from_secs(300);
+        let mut packet = TCPPacket::new(&tcpoptions::user_timeout(timeout), 0);
+        packet.set_seq(socket.recv_param.next);
+        packet.set_ack(socket.send_param.next + 100);
+        packet.set_flag(tcpflags::ACK);
+        packet.set_window_size(socket.send_param.window as u16);
+        // 未送信のデータをackするセグメントのオプションは使わない
+        tcp.established_handler(&mut socket, &packet, false)
+            .unwrap();
+        assert_eq!(socket.remote_user_timeout, None);
+        packet.set_ack(socket.send_param.next);
+        tcp.established_handler(&mut socket, &packet, false)
+            .unwrap();
+        assert_eq!(socket.remote_user_timeout, Some(timeout));
+    }
+
+    #[test]
+    fn test_challenge_ack_right_after_start() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        tcp.send_challenge_ack(&mut socket).unwrap();
+        assert_eq!(socket.sent.len(), 1);
+        assert_eq!(socket.sent[0].get_flag(), tcpflags::ACK);
+    }
+
+    #[test]
+    fn test_remote_user_timeout_opt_in() {
+        let mut socket = established_socket();
+        socket.remote_user_timeout = Some(Duration::from_secs(300));
+        // アプリケーションが許可するまでは相手の値を使わない
+        assert_eq!(socket.effective_user_timeout(), None);
+        socket.accept_remote_user_timeout = true;
+        assert_eq!(
+            socket.effective_user_timeout(),
+            Some(Duration::from_secs(300))
+        );
+        // 相手の値は下限と上限に収める
+        socket.remote_user_timeout = Some(Duration::from_secs(10));
+        assert_eq!(
+            socket.effective_user_timeout(),
+            Some(Duration::from_secs(100))
+        );
+        // 自分で指定した値が優先される
+        socket.user_timeout = Some(Duration::from_secs(30));
+        assert_eq!(
+            socket.effective_user_timeout(),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_md5_segment_size() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.md5_key = Some(b"secret".to_vec());
+        // MD5署名オプション(18)を4バイト境界に揃えた分だけ小さく分割する
+        assert_eq!(socket.mss(), MSS - 20);
+        socket.nodelay = true;
+        socket.send_buffer.push(&[0; 3000]);
+        tcp.transmit(&mut socket, false).unwrap();
+        let lens: Vec<u32> = socket
+            .retransmission_queue
+            .iter()
+            .map(|item| item.len)
+            .collect();
+        assert_eq!(lens, vec![1440, 1440, 120]);
+
+        // 再送でまとめる時もMSSを超えない
+        let count = socket.retransmission_queue.len();
+        socket.retransmit(0).unwrap();
+        assert_eq!(socket.retransmission_queue.len(), count);
+        assert_eq!(socket.retransmission_queue[0].len, 1440);
+    }
+
+    #[test]
+    fn test_syn_data_size() {
+        let mut socket = established_socket();
+        socket.status = TcpStatus::SynSent;
+        socket.fast_open_cookie = Some(vec![0; 8]);
+        // MSS(4) + ウィンドウスケール(3) + Fast Open(10)を4バイト境界に揃える
+        assert_eq!(socket.syn_data_size(), MSS - 20);
+        socket.md5_key = Some(b"secret".to_vec());
+        socket.user_timeout = Some(Duration::from_secs(300));
+        socket.advertise_user_timeout = true;
+        // さらにMD5署名(18)とUser Timeout(4)
+        assert_eq!(socket.syn_data_size(), MSS - 40);
+    }
+
+    #[test]
+    fn test_early_accepted_push_seq() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.status = TcpStatus::SynRcvd;
+        socket.early_accepted = true;
+        // SYN-ACKがまだackされていない
+        socket.send_param.unacked_seq = socket.send_param.initial_seq;
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        tcp.send_urgent(sock_id, &[1]).unwrap();
+        let table = tcp.sockets.read().unwrap();
+        // データはSYNの次のseqから始まる
+        assert_eq!(table[&sock_id].send_param.push_seq, 1002);
+        assert_eq!(table[&sock_id].send_param.urgent_pointer, Some(1002));
+    }
+
+    #[test]
+    fn test_fast_open_stripped_syn_keeps_cookie() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.status = TcpStatus::SynSent;
+        socket.send_param.unacked_seq = socket.send_param.initial_seq;
+        socket.fast_open_cookie = Some(vec![0; 8]);
+        tcp.fast_open_cookies
+            .lock()
+            .unwrap()
+            .insert(socket.remote_addr, vec![0; 8]);
+        socket.send_buffer.push(&[1; 10]);
+        socket
+            .send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[1; 10])
+            .unwrap();
+        socket.send_param.next = socket.send_param.initial_seq + 11;
+        // タイムアウトしてデータなしで再送したSYNだけがackされた
+        socket.retransmit(0).unwrap();
+        let mut syn_ack = TCPPacket::new(&[], 0);
+        syn_ack.set_seq(socket.recv_param.initial_seq);
+        syn_ack.set_ack(socket.send_param.initial_seq + 1);
+        syn_ack.set_flag(tcpflags::SYN | tcpflags::ACK);
+        syn_ack.set_window_size(65535);
+        tcp.synsent_handler(&mut socket, &syn_ack).unwrap();
+        assert_eq!(socket.status, TcpStatus::Established);
+        assert!(tcp
+            .fast_open_cookies
+            .lock()
+            .unwrap()
+            .contains_key(&socket.remote_addr));
+        // SYNに載せたデータは接続後に送り直す
+        assert_eq!(socket.sent.last().unwrap().payload(), &[1; 10]);
+    }
+
+    #[test]
+    fn test_fast_open_option_budget() {
+        let mut socket = established_socket();
+        socket.status = TcpStatus::SynSent;
+        socket.md5_key = Some(b"secret".to_vec());
+        socket.user_timeout = Some(Duration::from_secs(300));
+        socket.advertise_user_timeout = true;
+        // MSS(4) + MD5署名(18) + User Timeout(4) + ウィンドウスケール(3)に
+        // 16バイトのcookie(18)を加えると40バイトを超えるので，Fast Openを諦める
+        socket.fast_open_cookie = Some(vec![0; 16]);
+        assert_eq!(socket.syn_data_size(), 0);
+        // サイズを調べるだけではソケットの状態を変えない
+        assert!(socket.fast_open_cookie.is_some());
+        socket
+            .send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[])
+            .unwrap();
+        assert!(socket.fast_open_cookie.is_none());
+        let syn = socket.sent.last().unwrap();
+        assert!(syn.get_option(tcpoptions::FAST_OPEN).is_none());
+        assert!(syn.get_option(tcpoptions::MD5_SIGNATURE).is_some());
+        assert!(syn.get_option(tcpoptions::USER_TIMEOUT).is_some());
+    }
+
+    #[test]
+    fn test_md5_option_first() {
+        let mut socket = established_socket();
+        socket.md5_key = Some(b"secret".to_vec());
+        socket.user_timeout = Some(Duration::from_secs(300));
+        socket.advertise_user_timeout = true;
+        socket.user_timeout_pending = true;
+        socket.fast_open_cookie = Some(vec![0; 16]);
+        // 署名の場所を最初に確保し，収まらないオプションの方を省く
+        socket
+            .send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::SYN | tcpflags::ACK,
+                &[],
+            )
+            .unwrap();
+        let packet = socket.sent.last().unwrap();
+        assert_eq!(packet.packet()[20], tcpoptions::MD5_SIGNATURE);
+        assert!(packet.get_option(tcpoptions::USER_TIMEOUT).is_some());
+        assert!(packet.get_option(tcpoptions::FAST_OPEN).is_none());
+        // データのないACKでもUser Timeoutと署名を一緒に付けられる
+        socket.user_timeout_pending = true;
+        socket.send_ack().unwrap();
+        let packet = socket.sent.last().unwrap();
+        assert_eq!(packet.packet()[20], tcpoptions::MD5_SIGNATURE);
+        assert!(packet.get_option(tcpoptions::USER_TIMEOUT).is_some());
+    }
+
+    #[test]
+    fn test_transmission_count_saturates() {
+        // User Timeoutが長ければ255回を超えて再送することもある
+        let mut socket = established_socket();
+        send_segments(&mut socket, 1);
+        socket.retransmission_queue[0].transmission_count = u8::MAX;
+        socket.retransmit(0).unwrap();
+        assert_eq!(socket.retransmission_queue[0].transmission_count, u8::MAX);
+    }
+
+    #[test]
+    fn test_keepalive_timeout() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.keepalive = Some(Keepalive {
+            idle: Duration::from_secs(1),
+            interval: Duration::from_secs(1),
+            count: 3,
+        });
+        socket.last_received = SystemTime::now() - Duration::from_secs(10);
+
+        // 受信ウィンドウ外のセグメントでは相手が生きているとみなさない
+        let mut packet = TCPPacket::new(&[], 0);
+        packet.set_seq(socket.recv_param.next + socket.recv_param.window + 100);
+        packet.set_ack(socket.send_param.next);
+        packet.set_flag(tcpflags::ACK);
+        packet.set_window_size(socket.send_param.window as u16);
+        tcp.established_handler(&mut socket, &packet, false)
+            .unwrap();
+        for probes in 1..=3 {
+            tcp.keepalive(&mut socket).unwrap();
+            assert_eq!(socket.keepalive_probes, probes);
+            assert_eq!(socket.status, TcpStatus::Established);
+        }
+        assert_eq!(socket.sent.len(), 3);
+
+        // 応答がないままcount個のプローブを送り終えたら接続を切る
+        tcp.keepalive(&mut socket).unwrap();
+        assert_eq!(socket.status, TcpStatus::Closed);
+        assert_eq!(socket.error, Some(SocketError::TimedOut));
+    }
+
+    #[test]
+    fn test_time_wait() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.status = TcpStatus::FinWait2;
+        let mut fin = TCPPacket::new(&[], 0);
+        fin.set_seq(socket.recv_param.next);
+        fin.set_ack(socket.send_param.next);
+        fin.set_flag(tcpflags::FIN | tcpflags::ACK);
+        fin.set_window_size(socket.send_param.window as u16);
+        tcp.finwait_handler(&mut socket, &fin, false).unwrap();
+        assert_eq!(socket.status, TcpStatus::TimeWait);
+        assert_eq!(socket.recv_param.next, 5002);
+        let min_deadline = SystemTime::now() + Duration::from_secs(2 * MAX_SEGMENT_LIFETIME - 1);
+        assert!(socket.close_deadline.unwrap() > min_deadline);
+
+        // 再送されたFINにはACKを返し，2MSLを測り直す
+        socket.close_deadline = Some(SystemTime::now());
+        tcp.finwait_handler(&mut socket, &fin, false).unwrap();
+        assert_eq!(socket.status, TcpStatus::TimeWait);
+        assert_eq!(socket.recv_param.next, 5002);
+        assert!(socket.close_deadline.unwrap() > min_deadline);
+
+        // closeしてもTIME_WAITの間は破棄しない
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        tcp.close(sock_id).unwrap();
+        let table = tcp.sockets.read().unwrap();
+        assert!(table[&sock_id].released);
+        assert_eq!(table[&sock_id].status, TcpStatus::TimeWait);
+    }
+
+    #[test]
+    fn test_linger_after_fin_acked() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.status = TcpStatus::FinWait2;
+        socket.linger = Some(Duration::from_secs(5));
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        // 自分のFINがackされていればすぐに戻るが，相手のFINは待ち続ける
+        tcp.close(sock_id).unwrap();
+        let mut table = tcp.sockets.write().unwrap();
+        let socket = table.get_mut(&sock_id).unwrap();
+        assert!(socket.released);
+        assert_eq!(socket.status, TcpStatus::FinWait2);
+        assert!(socket.close_deadline.is_some());
+
+        let mut fin = TCPPacket::new(&[], 0);
+        fin.set_seq(socket.recv_param.next);
+        fin.set_ack(socket.send_param.next);
+        fin.set_flag(tcpflags::FIN | tcpflags::ACK);
+        fin.set_window_size(socket.send_param.window as u16);
+        tcp.finwait_handler(socket, &fin, false).unwrap();
+        assert_eq!(socket.status, TcpStatus::TimeWait);
+    }
+
+    #[test]
+    fn test_partially_duplicate_segment() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        let next = socket.recv_param.next;
+        receive_data(&tcp, &mut socket, next, b"hello");
+        assert_eq!(socket.recv_param.next, next + 5);
+
+        // 受信済みの部分は捨てて，新しい部分だけを受信バッファに入れる
+        receive_data(&tcp, &mut socket, next + 3, b"lo world");
+        assert_eq!(socket.recv_param.next, next + 11);
+        let mut buffer = [0; 16];
+        let n = socket.recv_buffer.peek(0, &mut buffer);
+        assert_eq!(&buffer[..n], b"hello world");
+
+        // 全て受信済みのセグメントは何も変えない
+        receive_data(&tcp, &mut socket, next, b"hello");
+        assert_eq!(socket.recv_param.next, next + 11);
+        assert_eq!(socket.recv_buffer.len(), 11);
+    }
+
+    #[test]
+    fn test_delayed_ack() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.recv_param.quick_acks = 0;
+        let next = socket.recv_param.next;
+
+        // 1セグメント目ではACKを遅延させる
+        receive_data(&tcp, &mut socket, next, &[0; MSS]);
+        assert!(socket.sent.is_empty());
+        assert!(socket.recv_param.ack_deadline.is_some());
+
+        // 2セグメント目ですぐにACKを返す
+        receive_data(&tcp, &mut socket, next + MSS as u32, &[0; MSS]);
+        assert_eq!(socket.sent.len(), 1);
+        assert_eq!(socket.sent[0].get_ack(), next + 2 * MSS as u32);
+        assert!(socket.recv_param.ack_deadline.is_none());
+
+        // 順序が入れ替わったセグメントには1つ目でもすぐに重複ACKを返す
+        receive_data(&tcp, &mut socket, next + 2 * MSS as u32 + 100, &[0; 100]);
+        assert_eq!(socket.sent.len(), 2);
+        assert_eq!(socket.sent[1].get_ack(), next + 2 * MSS as u32);
+        assert_eq!(socket.recv_param.quick_acks, QUICK_ACK_SEGMENTS);
+    }
+
+    #[test]
+    fn test_delayed_ack_timeout() {
+        let tcp = Arc::new(TCP::without_threads());
+        let mut socket = established_socket();
+        socket.recv_param.quick_acks = 0;
+        let next = socket.recv_param.next;
+        receive_data(&tcp, &mut socket, next, &[0; 100]);
+        assert!(socket.sent.is_empty());
+
+        // 次のセグメントが来なくても，遅延ACKのタイマーでACKを返す
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        let start = Instant::now();
+        start_timer(&tcp);
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(2), "no delayed ack");
+            let table = tcp.sockets.read().unwrap();
+            if let Some(ack) = table[&sock_id].sent.first() {
+                assert_eq!(ack.get_ack(), next + 100);
+                break;
+            }
+            drop(table);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(DELAYED_ACK_TIMEOUT) / 2);
+    }
+
+    #[test]
+    fn test_window_update_threshold() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        let next = socket.recv_param.next;
+        // 受信バッファ(4380バイト)を埋めてウィンドウを0にする
+        for i in 0..3 {
+            receive_data(&tcp, &mut socket, next + (i * MSS) as u32, &[0; MSS]);
+        }
+        assert_eq!(socket.recv_param.window, 0);
+        socket.send_ack().unwrap();
+        assert_eq!(socket.sent.last().unwrap().get_window_size(), 0);
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        let sent = |tcp: &TCP| tcp.sockets.read().unwrap()[&sock_id].sent.len();
+        let before = sent(&tcp);
+
+        // min(MSS, バッファの半分)未満しか空いていなければウィンドウ更新を送らない
+        let mut buffer = [0; MSS];
+        tcp.recv(sock_id, &mut buffer[..1000]).unwrap();
+        assert_eq!(sent(&tcp), before);
+        let mut table = tcp.sockets.write().unwrap();
+        let socket = table.get_mut(&sock_id).unwrap();
+        socket.send_ack().unwrap();
+        // 送るACKでも小さくしか開いていないウィンドウは広告しない
+        assert_eq!(socket.sent.last().unwrap().get_window_size(), 0);
+        drop(table);
+
+        // MSS以上空いたらウィンドウ更新を送る
+        tcp.recv(sock_id, &mut buffer[..500]).unwrap();
+        let table = tcp.sockets.read().unwrap();
+        let update = table[&sock_id].sent.last().unwrap();
+        assert_eq!(table[&sock_id].sent.len(), before + 2);
+        assert_eq!(update.get_window_size(), 1500);
+    }
+
+    #[test]
+    fn test_sender_sws_avoidance() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        send_segments(&mut socket, 1);
+        // 相手のウィンドウの残りが小さく，送信中のセグメントのACKで開く見込みがあれば送らずに待つ
+        socket.send_param.window = MSS as u32 + 500;
+        socket.send_buffer.push(&[0; 3000]);
+        tcp.transmit(&mut socket, false).unwrap();
+        assert_eq!(socket.sent.len(), 1);
+        assert!(socket.send_param.sws_deadline.is_none());
+
+        // 送信中のセグメントがなければ，しばらく待ってもウィンドウが開かない時だけ小さく送る
+        let next = socket.send_param.next;
+        socket.send_param.window = 500;
+        receive_ack(&tcp, &mut socket, next);
+        assert_eq!(socket.sent.len(), 1);
+        assert!(socket.send_param.sws_deadline.is_some());
+        socket.send_param.sws_deadline = Some(SystemTime::now());
+        tcp.transmit(&mut socket, false).unwrap();
+        assert_eq!(socket.sent.len(), 2);
+        assert_eq!(socket.sent[1].payload().len(), 500);
+    }
+
+    #[test]
+    fn test_zero_window_probe() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.send_param.window = 0;
+        socket.send_buffer.push(&[0; 100]);
+        tcp.transmit(&mut socket, false).unwrap();
+        assert!(socket.sent.is_empty());
+        assert!(socket.send_param.persist_deadline.is_some());
+
+        // パーシストタイマーが切れたらウィンドウプローブを送り，間隔を倍にする
+        socket.send_param.persist_deadline = Some(SystemTime::now());
+        probe_zero_window(&mut socket).unwrap();
+        assert_eq!(socket.sent.len(), 1);
+        assert_eq!(socket.sent[0].get_seq(), socket.send_param.next - 1);
+        assert!(socket.sent[0].payload().is_empty());
+        assert_eq!(socket.send_param.persist_backoff, 1);
+    }
+
+    #[test]
+    fn test_recv_urgent() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        let next = socket.recv_param.next;
+        let segment = |seq: u32, payload: &[u8], pointer: u16| {
+            let mut packet = TCPPacket::new(&[], payload.len());
+            packet.set_seq(seq);
+            packet.set_ack(1001);
+            packet.set_flag(tcpflags::ACK | tcpflags::URG);
+            packet.set_urgent_pointer(pointer);
+            packet.set_window_size(4380);
+            packet.set_payload(payload);
+            packet
+        };
+        // 緊急ポインタが次のセグメントの'e'を指し，2つのセグメントで同じ緊急データを知らせる
+        tcp.established_handler(&mut socket, &segment(next, b"abc", 5), false)
+            .unwrap();
+        assert_eq!(socket.urgent_data, None);
+        tcp.established_handler(&mut socket, &segment(next + 3, b"def", 2), false)
+            .unwrap();
+        assert_eq!(socket.urgent_data, Some(b'e'));
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+
+        // 緊急データの手前までしか読めず，緊急データのバイトは通常のデータから除かれる
+        let mut buffer = [0; 16];
+        let n = tcp.recv(sock_id, &mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"abcd");
+        let n = tcp.recv(sock_id, &mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"f");
+        // 緊急データは一度だけ読める
+        assert_eq!(tcp.recv_urgent(sock_id).unwrap(), Some(b'e'));
+        assert_eq!(tcp.recv_urgent(sock_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_push_on_last_segment_of_write() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.nodelay = true;
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        // 書き込みの最後のバイトを含むセグメントだけにPSHを立てる
+        tcp.send(sock_id, &[0; 2 * MSS + 100]).unwrap();
+        tcp.send(sock_id, &[0; 50]).unwrap();
+        let table = tcp.sockets.read().unwrap();
+        let pushed: Vec<(usize, bool)> = table[&sock_id]
+            .sent
+            .iter()
+            .map(|packet| {
+                let push = packet.get_flag() & tcpflags::PSH > 0;
+                (packet.payload().len(), push)
+            })
+            .collect();
+        assert_eq!(
+            pushed,
+            vec![(MSS, false), (MSS, false), (100, true), (50, true)]
+        );
+    }
+
+    #[test]
+    fn test_persist_timeout_backoff() {
+        let timeouts: Vec<u64> = (0..7).map(|b| persist_timeout(b).as_secs()).collect();
+        assert_eq!(timeouts, [3, 6, 12, 24, 48, 60, 60]);
+        assert_eq!(persist_timeout(u8::MAX).as_secs(), MAX_PERSIST_TIMEOUT);
+    }
+
+    #[test]
+    fn test_ecn_handshake() {
+        let tcp = TCP::without_threads();
+        let mut client = established_socket();
+        client.status = TcpStatus::SynSent;
+        client.send_param.unacked_seq = client.send_param.initial_seq;
+        assert_eq!(tcp.syn_flag(&mut client), tcpflags::SYN);
+        assert!(!client.ecn);
+        // ECN-setup SYNにはECEとCWRを立てる
+        tcp.set_ecn(true);
+        assert_eq!(
+            tcp.syn_flag(&mut client),
+            tcpflags::SYN | tcpflags::ECE | tcpflags::CWR
+        );
+        assert!(client.ecn);
+
+        // リスニングソケットはECN-setup SYN-ACK(ECEのみ)で応じる
+        let listener = tcp.listen(RECEIVER_ADDR, 50000, 1).unwrap();
+        let mut syn = TCPPacket::new(&[], 0);
+        syn.set_src(40000);
+        syn.set_dest(50000);
+        syn.set_seq(5000);
+        syn.set_flag(tcpflags::SYN | tcpflags::ECE | tcpflags::CWR);
+        syn.set_window_size(4380);
+        tcp.listen_handler(tcp.sockets.write().unwrap(), listener, &syn, SENDER_ADDR)
+            .unwrap();
+        let table = tcp.sockets.read().unwrap();
+        let server = &table[&SockID(RECEIVER_ADDR, SENDER_ADDR, 50000, 40000)];
+        assert!(server.ecn);
+        let syn_ack = server.sent[0].clone();
+        assert_eq!(
+            syn_ack.get_flag(),
+            tcpflags::SYN | tcpflags::ACK | tcpflags::ECE
+        );
+        drop(table);
+
+        // ECN-setup SYN-ACKを受け取ればECNを使い，そうでなければ使わない
+        let mut syn_ack = TCPPacket::new(&[], 0);
+        syn_ack.set_seq(5000);
+        syn_ack.set_ack(client.send_param.next);
+        syn_ack.set_flag(tcpflags::SYN | tcpflags::ACK | tcpflags::ECE);
+        syn_ack.set_window_size(4380);
+        let mut plain = established_socket();
+        plain.status = TcpStatus::SynSent;
+        plain.send_param.unacked_seq = plain.send_param.initial_seq;
+        plain.ecn = true;
+        tcp.synsent_handler(&mut client, &syn_ack).unwrap();
+        assert_eq!(client.status, TcpStatus::Established);
+        assert!(client.ecn);
+        syn_ack.set_flag(tcpflags::SYN | tcpflags::ACK);
+        tcp.synsent_handler(&mut plain, &syn_ack).unwrap();
+        assert!(!plain.ecn);
+    }
+
+    #[test]
+    fn test_ece_once_per_window() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        socket.ecn = true;
+        send_segments(&mut socket, 4);
+        // 新しいデータセグメントだけをECT(0)で送る
+        assert_eq!(socket.tos, ECN_ECT0);
+        let ece_ack = |socket: &Socket, ack: u32| {
+            let mut packet = TCPPacket::new(&[], 0);
+            packet.set_seq(socket.recv_param.next);
+            packet.set_ack(ack);
+            packet.set_flag(tcpflags::ACK | tcpflags::ECE);
+            packet.set_window_size(socket.send_param.window as u16);
+            packet
+        };
+        let large = 10 * MSS as u32;
+
+        // ECEを受け取ったらウィンドウを縮め，次のデータセグメントにCWRを立てる
+        socket.congestion.set_window(large, large);
+        let packet = ece_ack(&socket, socket.send_param.unacked_seq + MSS as u32);
+        assert!(tcp.process_ack(&mut socket, &packet).unwrap());
+        assert!(socket.congestion.cwnd() < large);
+        assert!(socket.send_param.cwr_pending);
+        assert_eq!(socket.send_param.cwr_seq, socket.send_param.next);
+
+        // 同じウィンドウ内のECEでは縮めない
+        socket.congestion.set_window(large, large);
+        let packet = ece_ack(&socket, socket.send_param.unacked_seq + MSS as u32);
+        assert!(tcp.process_ack(&mut socket, &packet).unwrap());
+        assert!(socket.congestion.cwnd() >= large);
+
+        // 縮めた時に送信済みだったデータが全てackされた後のECEでは再び縮める
+        let packet = ece_ack(&socket, socket.send_param.next);
+        assert!(tcp.process_ack(&mut socket, &packet).unwrap());
+        assert!(socket.congestion.cwnd() < large);
+    }
+
+    #[test]
+    fn test_cwr_clears_ece() {
+        let mut socket = established_socket();
+        socket.ecn = true;
+        socket.process_ecn(tcpflags::ACK, true).unwrap();
+        assert!(socket.recv_param.ece_pending);
+        // CWRを受け取るまでは全てのACKにECEを立て続ける
+        for _ in 0..2 {
+            socket.send_ack().unwrap();
+            assert!(socket.sent.last().unwrap().get_flag() & tcpflags::ECE > 0);
+            socket.process_ecn(tcpflags::ACK, false).unwrap();
+        }
+        // ACKはECT(0)で送らない
+        assert_eq!(socket.tos, 0);
+        socket
+            .process_ecn(tcpflags::ACK | tcpflags::CWR, false)
+            .unwrap();
+        assert!(!socket.recv_param.ece_pending);
+        socket.send_ack().unwrap();
+        assert_eq!(socket.sent.last().unwrap().get_flag() & tcpflags::ECE, 0);
+    }
+
+    #[test]
+    fn test_nagle() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        // 未ackのデータがなければMSS未満でもすぐに送る
+        socket.send_buffer.push(&[0; 100]);
+        tcp.transmit(&mut socket, false).unwrap();
+        assert_eq!(socket.sent.len(), 1);
+
+        // 未ackのデータがある間はMSS未満のセグメントを溜めておく
+        socket.send_buffer.push(&[0; 100]);
+        tcp.transmit(&mut socket, false).unwrap();
+        socket.send_buffer.push(&[0; 100]);
+        tcp.transmit(&mut socket, false).unwrap();
+        assert_eq!(socket.sent.len(), 1);
+
+        // ACKを受信したら溜めていた分をまとめて送る
+        let next = socket.send_param.next;
+        receive_ack(&tcp, &mut socket, next);
+        assert_eq!(socket.sent.len(), 2);
+        assert_eq!(socket.sent[1].payload().len(), 200);
+
+        // nodelayなら未ackのデータがあっても溜めない
+        socket.nodelay = true;
+        socket.send_buffer.push(&[0; 100]);
+        tcp.transmit(&mut socket, false).unwrap();
+        assert_eq!(socket.sent.len(), 3);
+        assert!(socket.send_param.flight_size() > 100);
+    }
+
+    #[test]
+    fn test_fast_retransmit_and_new_reno_recovery() {
+        let tcp = TCP::without_threads();
+        let mut socket = established_socket();
+        send_segments(&mut socket, 8);
+        let flight_size = socket.send_param.flight_size();
+        let lost = socket.send_param.unacked_seq;
+
+        // 3つ目の重複ACKで高速再送して高速回復に入る
+        for _ in 0..2 {
+            receive_ack(&tcp, &mut socket, lost);
+            assert!(!socket.send_param.in_fast_recovery);
+        }
+        receive_ack(&tcp, &mut socket, lost);
+        assert!(socket.send_param.in_fast_recovery);
+        assert_eq!(socket.send_param.recover, socket.send_param.next);
+        assert_eq!(socket.send_param.inflation, 3 * MSS as u32);
+        assert_eq!(socket.congestion.ssthresh(), flight_size / 2);
+        assert_eq!(socket.congestion.cwnd(), flight_size / 2);
+        assert_eq!(socket.retransmission_queue[0].seq, lost);
+        assert_eq!(socket.retransmission_queue[0].transmission_count, 2);
+
+        // 以降の重複ACKではウィンドウを膨らませるだけ
+        receive_ack(&tcp, &mut socket, lost);
+        assert_eq!(socket.send_param.inflation, 4 * MSS as u32);
+        assert_eq!(socket.retransmission_queue[0].transmission_count, 2);
+
+        // 部分ACKでは次の欠落セグメントを再送し，ackされた分だけウィンドウを縮める
+        let partial = lost + 2 * MSS as u32;
+        receive_ack(&tcp, &mut socket, partial);
+        assert!(socket.send_param.in_fast_recovery);
+        assert_eq!(socket.send_param.unacked_seq, partial);
+        assert_eq!(socket.send_param.inflation, 3 * MSS as u32);
+        assert_eq!(socket.retransmission_queue[0].seq, partial);
+        assert_eq!(socket.retransmission_queue[0].transmission_count, 2);
+
+        // 完全ACKで高速回復を抜け，ウィンドウはssthreshに戻る
+        let recover = socket.send_param.recover;
+        receive_ack(&tcp, &mut socket, recover);
+        assert!(!socket.send_param.in_fast_recovery);
+        assert_eq!(socket.send_param.inflation, 0);
+        assert_eq!(socket.congestion.cwnd(), socket.congestion.ssthresh());
+        assert!(socket.retransmission_queue.is_empty());
+    }
+
+    #[test]
+    fn test_retransmission_timeout_in_fast_recovery() {
+        let tcp = Arc::new(TCP::without_threads());
+        let mut socket = established_socket();
+        send_segments(&mut socket, 8);
+        let flight_size = socket.send_param.flight_size();
+        let lost = socket.send_param.unacked_seq;
+        for _ in 0..3 {
+            receive_ack(&tcp, &mut socket, lost);
+        }
+        assert!(socket.send_param.in_fast_recovery);
+
+        // 高速再送したセグメントも失われ，再送タイマーが切れた
+        socket.retransmission_queue[0].latest_transmission_time =
+            SystemTime::now() - Duration::from_secs(RETRANSMITTION_TIMEOUT);
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        start_timer(&tcp);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(Instant::now() < deadline, "no retransmission timeout");
+            let table = tcp.sockets.read().unwrap();
+            if table[&sock_id].retransmission_queue[0].transmission_count == 3 {
+                break;
+            }
+            drop(table);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // 高速回復を抜けて輻輳ウィンドウを1MSSに戻すが，同じ損失でssthreshは下げ直さない
+        let table = tcp.sockets.read().unwrap();
+        let socket = &table[&sock_id];
+        assert!(!socket.send_param.in_fast_recovery);
+        assert_eq!(socket.send_param.inflation, 0);
+        assert_eq!(socket.send_param.dup_ack_count, 0);
+        assert_eq!(socket.congestion.cwnd(), socket.mss() as u32);
+        assert_eq!(socket.congestion.ssthresh(), flight_size / 2);
+    }
+}
@@ -0,0 +1,174 @@
+use std::cmp;
+use std::time::Duration;
+
+pub const END: u8 = 0;
+pub const NOP: u8 = 1;
+pub const MSS: u8 = 2;
+pub const WINDOW_SCALE: u8 = 3;
+pub const MD5_SIGNATURE: u8 = 19;
+pub const USER_TIMEOUT: u8 = 28;
+pub const FAST_OPEN: u8 = 34;
+
+pub const MAX_OPTIONS_LEN: usize = 40; // データオフセット(最大15ワード)からヘッダの20バイトを除いた長さ
+
+const MSS_LEN: u8 = 4;
+pub const DEFAULT_MSS: u16 = 536; // MSSオプションがない時に仮定するMSS(RFC1122)
+
+const WINDOW_SCALE_LEN: u8 = 3;
+const MAX_WINDOW_SCALE: u8 = 14; // これより大きいシフト数は14とみなす(RFC7323 2.3)
+
+const MD5_SIGNATURE_LEN: u8 = 18;
+pub const MD5_MAX_KEY_LEN: usize = 80; // 鍵の最大長(LinuxのTCP_MD5SIG_MAXKEYLEN)
+
+const FAST_OPEN_MIN_COOKIE_LEN: usize = 4;
+const FAST_OPEN_MAX_COOKIE_LEN: usize = 16;
+
+const USER_TIMEOUT_LEN: u8 = 4;
+const USER_TIMEOUT_GRANULARITY: u16 = 1 << 15; // 立っていれば単位は分，でなければ秒
+const USER_TIMEOUT_MAX_VALUE: u16 = USER_TIMEOUT_GRANULARITY - 1;
+
+/// RFC5482のUser Timeoutオプションを生成する．15ビットの秒で表せなければ分で表す
+pub fn user_timeout(timeout: Duration) -> [u8; 4] {
+    let secs = timeout.as_secs();
+    let value = if secs <= USER_TIMEOUT_MAX_VALUE as u64 {
+        secs as u16
+    } else {
+        USER_TIMEOUT_GRANULARITY | cmp::min(secs / 60, USER_TIMEOUT_MAX_VALUE as u64) as u16
+    };
+    let value = value.to_be_bytes();
+    [USER_TIMEOUT, USER_TIMEOUT_LEN, value[0], value[1]]
+}
+
+/// User Timeoutオプションのデータ部分を解釈する
+pub fn parse_user_timeout(data: &[u8]) -> Option<Duration> {
+    if data.len() != (USER_TIMEOUT_LEN - 2) as usize {
+        return None;
+    }
+    let value = u16::from_be_bytes([data[0], data[1]]);
+    let timeout = (value & USER_TIMEOUT_MAX_VALUE) as u64;
+    if value & USER_TIMEOUT_GRANULARITY > 0 {
+        Some(Duration::from_secs(timeout * 60))
+    } else {
+        Some(Duration::from_secs(timeout))
+    }
+}
+
+/// MSSオプションを生成する．SYNでだけ送る
+pub fn mss(mss: u16) -> [u8; 4] {
+    let mss = mss.to_be_bytes();
+    [MSS, MSS_LEN, mss[0], mss[1]]
+}
+
+/// MSSオプションのデータ部分を解釈する
+pub fn parse_mss(data: &[u8]) -> Option<u16> {
+    if data.len() != (MSS_LEN - 2) as usize {
+        return None;
+    }
+    Some(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// RFC7323のウィンドウスケールオプションを生成する．SYNでだけ送る
+pub fn window_scale(shift: u8) -> [u8; 3] {
+    [WINDOW_SCALE, WINDOW_SCALE_LEN, shift]
+}
+
+/// ウィンドウスケールオプションのデータ部分(シフト数)を解釈する
+pub fn parse_window_scale(data: &[u8]) -> Option<u8> {
+    if data.len() != (WINDOW_SCALE_LEN - 2) as usize {
+        return None;
+    }
+    Some(cmp::min(data[0], MAX_WINDOW_SCALE))
+}
+
+/// RFC7413のFast Openオプションを生成する．cookieが空ならcookieを要求するオプションになる
+pub fn fast_open(cookie: &[u8]) -> Vec<u8> {
+    let mut option = vec![FAST_OPEN, (cookie.len() + 2) as u8];
+    option.extend_from_slice(cookie);
+    option
+}
+
+/// Fast Openオプションのデータ部分(cookie)を検証する．cookieの要求なら空のスライスを返す
+pub fn parse_fast_open(data: &[u8]) -> Option<&[u8]> {
+    let cookie_len = (FAST_OPEN_MIN_COOKIE_LEN..=FAST_OPEN_MAX_COOKIE_LEN).contains(&data.len())
+        && data.len() & 1 == 0;
+    if data.is_empty() || cookie_len {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+/// RFC2385のMD5署名オプションを生成する．署名は0で埋めておき，パケットを組み立ててから書き込む
+pub fn md5_signature() -> [u8; MD5_SIGNATURE_LEN as usize] {
+    let mut option = [0; MD5_SIGNATURE_LEN as usize];
+    option[0] = MD5_SIGNATURE;
+    option[1] = MD5_SIGNATURE_LEN;
+    option
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_timeout_round_trip() {
+        let option = user_timeout(Duration::from_secs(300));
+        assert_eq!(option, [USER_TIMEOUT, USER_TIMEOUT_LEN, 0x01, 0x2c]);
+        assert_eq!(
+            parse_user_timeout(&option[2..]),
+            Some(Duration::from_secs(300))
+        );
+        // 15ビットの秒で表せなければ分で表す
+        let option = user_timeout(Duration::from_secs(40000));
+        assert!(option[2] & 0x80 > 0);
+        assert_eq!(
+            parse_user_timeout(&option[2..]),
+            Some(Duration::from_secs(40000 / 60 * 60))
+        );
+        assert_eq!(parse_user_timeout(&[0x01]), None);
+        assert_eq!(parse_user_timeout(&[0x01, 0x2c, 0x00]), None);
+    }
+
+    #[test]
+    fn test_parse_mss() {
+        assert_eq!(mss(1460), [MSS, MSS_LEN, 0x05, 0xb4]);
+        assert_eq!(parse_mss(&[0x05, 0xb4]), Some(1460));
+        assert_eq!(parse_mss(&[]), None);
+        assert_eq!(parse_mss(&[0x05, 0xb4, 0x00]), None);
+    }
+
+    #[test]
+    fn test_window_scale_round_trip() {
+        let option = window_scale(7);
+        assert_eq!(option, [WINDOW_SCALE, WINDOW_SCALE_LEN, 7]);
+        assert_eq!(parse_window_scale(&option[2..]), Some(7));
+        // 14より大きいシフト数は14とみなす
+        assert_eq!(parse_window_scale(&[15]), Some(MAX_WINDOW_SCALE));
+        assert_eq!(parse_window_scale(&[]), None);
+        assert_eq!(parse_window_scale(&[7, 0]), None);
+    }
+
+    #[test]
+    fn test_fast_open_round_trip() {
+        let cookie = [1, 2, 3, 4, 5, 6, 7, 8];
+        let option = fast_open(&cookie);
+        assert_eq!(option[..2], [FAST_OPEN, 10]);
+        assert_eq!(parse_fast_open(&option[2..]), Some(&cookie[..]));
+        // cookieの要求
+        assert_eq!(fast_open(&[]), [FAST_OPEN, 2]);
+        assert_eq!(parse_fast_open(&[]), Some(&[][..]));
+        // cookieは4から16バイトの偶数長
+        assert_eq!(parse_fast_open(&[1, 2]), None);
+        assert_eq!(parse_fast_open(&[1, 2, 3, 4, 5]), None);
+        assert_eq!(parse_fast_open(&[0; 18]), None);
+        assert!(parse_fast_open(&[0; 16]).is_some());
+    }
+
+    #[test]
+    fn test_md5_signature() {
+        let option = md5_signature();
+        assert_eq!(option.len(), MD5_SIGNATURE_LEN as usize);
+        assert_eq!(option[..2], [MD5_SIGNATURE, MD5_SIGNATURE_LEN]);
+        assert!(option[2..].iter().all(|&b| b == 0));
+    }
+}